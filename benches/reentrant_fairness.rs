@@ -0,0 +1,73 @@
+//! Throughput against tail latency of a fair and a non-fair `ReentrantLock`.
+//!
+//! Every thread takes the lock in a loop for a fixed time, holding it for a short critical
+//! section. The non-fair lock lets the unlocking thread take it again right away, so it
+//! completes more acquisitions, while the fair one bounds how long any single acquisition
+//! waits. Run with `cargo bench --bench reentrant_fairness`.
+
+use multi_thread::lock::ReentrantLock;
+use std::cell::Cell;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 4;
+const RUN_FOR: Duration = Duration::from_secs(2);
+
+fn critical_section(counter: &Cell<u64>) {
+    for _ in 0..100 {
+        counter.set(black_box(counter.get() + 1));
+    }
+}
+
+/// Returns the wait of every acquisition in nanoseconds, sorted.
+fn run(fair: bool) -> Vec<u64> {
+    let lock = Arc::new(ReentrantLock::new(Cell::new(0u64), fair));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Arc::new(Barrier::new(THREADS + 1));
+    let handlers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let (lock, stop, start) = (lock.clone(), stop.clone(), start.clone());
+            thread::spawn(move || {
+                let mut waits = Vec::new();
+                start.wait();
+                while !stop.load(Ordering::Relaxed) {
+                    let begin = Instant::now();
+                    let guard = lock.lock();
+                    waits.push(begin.elapsed().as_nanos() as u64);
+                    critical_section(&guard);
+                }
+                waits
+            })
+        })
+        .collect();
+    start.wait();
+    thread::sleep(RUN_FOR);
+    stop.store(true, Ordering::Relaxed);
+    let mut waits: Vec<u64> = handlers.into_iter().flat_map(|h| h.join().unwrap()).collect();
+    waits.sort_unstable();
+    waits
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p) as usize;
+    Duration::from_nanos(sorted[index])
+}
+
+fn main() {
+    println!("{THREADS} threads for {RUN_FOR:?} each");
+    for fair in [false, true] {
+        let waits = run(fair);
+        println!(
+            "{:>8}: {:>10.0} acquisitions/s  p50 {:>10?}  p99 {:>10?}  p99.9 {:>10?}  max {:>10?}",
+            if fair { "fair" } else { "non-fair" },
+            waits.len() as f64 / RUN_FOR.as_secs_f64(),
+            percentile(&waits, 0.5),
+            percentile(&waits, 0.99),
+            percentile(&waits, 0.999),
+            percentile(&waits, 1.0),
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::lock::error::BarrierError;
+use crate::lock::interrupt;
+use crate::lock::utils::{Backoff, Node, State, WaitQueue, WaitQueueGuard};
+use crate::lock::wait::{Parker, SpinThenPark, WaitStrategy};
+
+/// Reusable barrier for a fixed number of parties. Every state change happens with the
+/// wait queue locked, the atomics only make the fields shareable.
+pub struct CyclicBarrier {
+    parties: usize,
+    queue: WaitQueue,
+    // parties still to arrive in the current generation
+    count: AtomicUsize,
+    generation: AtomicUsize,
+    broken: AtomicBool,
+    action: Option<Box<dyn Fn() + Send + Sync>>,
+    strategy: Box<dyn WaitStrategy>,
+}
+
+/// Breaks the barrier when dropped while its thread is panicking, so the other parties
+/// are not left waiting for it.
+#[must_use]
+pub struct BarrierGuard<'a> {
+    barrier: &'a CyclicBarrier,
+}
+
+impl CyclicBarrier {
+    pub fn new(parties: usize) -> Self {
+        assert!(parties > 0);
+        Self {
+            parties,
+            queue: WaitQueue::new(),
+            count: AtomicUsize::new(parties),
+            generation: AtomicUsize::new(0),
+            broken: AtomicBool::new(false),
+            action: None,
+            strategy: Box::new(SpinThenPark),
+        }
+    }
+
+    /// Creates a barrier whose `action` is run by the last arriving party before the
+    /// others are released. A panicking action breaks the barrier.
+    ///
+    /// The action runs with the barrier unlocked and may call any of its methods but
+    /// `wait`, which would wait for the action itself. Resetting or breaking the barrier from
+    /// the action fails its generation with `BrokenBarrier`.
+    pub fn with_action<F>(parties: usize, action: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            action: Some(Box::new(action)),
+            ..Self::new(parties)
+        }
+    }
+
+    /// Sets how parties wait for the others, by default [`SpinThenPark`].
+    pub fn with_wait_strategy(self, strategy: impl WaitStrategy + 'static) -> Self {
+        Self {
+            strategy: Box::new(strategy),
+            ..self
+        }
+    }
+
+    /// Waits until all parties arrived. Returns the arrival index, `parties - 1` for the
+    /// first party to arrive and 0 for the last one.
+    pub fn wait(&self) -> Result<usize, BarrierError> {
+        self.wait_until(None, false)
+    }
+
+    /// Like [`wait`](Self::wait) but gives up after `timeout`, breaking the barrier. A
+    /// timeout too large to represent waits without a deadline.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<usize, BarrierError> {
+        self.wait_until(Instant::now().checked_add(timeout), false)
+    }
+
+    /// Waits until all parties arrived, `deadline` passes or the thread is interrupted.
+    /// The latter two break the barrier, except for a party arriving while the action of
+    /// the previous generation runs: it has not joined a generation yet and gives up
+    /// leaving the barrier intact.
+    pub fn wait_interruptibly(&self, deadline: Option<Instant>) -> Result<usize, BarrierError> {
+        self.wait_until(deadline, true)
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>, interruptible: bool) -> Result<usize, BarrierError> {
+        let backoff = Backoff::new();
+        let node = loop {
+            let mut queue = self.queue.lock();
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(BarrierError::BrokenBarrier);
+            }
+            let count = self.count.load(Ordering::Relaxed);
+            if count == 0 {
+                // every party arrived and the action runs, this one belongs to the next
+                // generation
+                self.await_trip(queue, &backoff, deadline, interruptible)?;
+                continue;
+            }
+            let index = count - 1;
+            self.count.store(index, Ordering::Relaxed);
+            if index == 0 {
+                let generation = self.generation.load(Ordering::Relaxed);
+                drop(queue);
+                return self.trip(generation);
+            }
+            if interruptible && interrupt::interrupted() {
+                self.break_barrier(&mut queue);
+                return Err(BarrierError::Interrupted);
+            }
+            if deadline.is_some_and(|end| end <= Instant::now()) {
+                self.break_barrier(&mut queue);
+                return Err(BarrierError::Timeout);
+            }
+            // the node carries the arrival index back to the woken party
+            let node = Box::into_raw(Box::new(Node::new(index as isize)));
+            queue.push_back(node);
+            break node;
+        };
+        let res = loop {
+            match unsafe { (*node).state() } {
+                State::ACQUIRED => break Ok(unsafe { (*node).acquires } as usize),
+                State::RUNNING => break Err(BarrierError::BrokenBarrier),
+                _ => {}
+            }
+            let interrupted = interruptible && interrupt::is_interrupted();
+            if interrupted || deadline.is_some_and(|end| end <= Instant::now()) {
+                let mut queue = self.queue.lock();
+                if unsafe { (*node).state() } == State::PARK {
+                    if self.count.load(Ordering::Relaxed) == 0 {
+                        // too late to give up, the generation is released after the action
+                        drop(queue);
+                        self.strategy.wait(&backoff, &Parker::new(None));
+                        continue;
+                    }
+                    queue.remove(node);
+                    unsafe { (*node).set_state(State::CANCELLED) };
+                    self.break_barrier(&mut queue);
+                    if interrupted {
+                        interrupt::interrupted();
+                        break Err(BarrierError::Interrupted);
+                    }
+                    break Err(BarrierError::Timeout);
+                }
+                continue;
+            }
+            self.strategy.wait(&backoff, &Parker::new(deadline));
+        };
+        drop(unsafe { Box::from_raw(node) });
+        res
+    }
+
+    /// Parks an arriving party until the running action ends its generation, the wake up
+    /// only means it can arrive again.
+    fn await_trip(
+        &self,
+        mut queue: WaitQueueGuard<'_>,
+        backoff: &Backoff,
+        deadline: Option<Instant>,
+        interruptible: bool,
+    ) -> Result<(), BarrierError> {
+        let node = Box::into_raw(Box::new(Node::new(0)));
+        queue.push_back(node);
+        drop(queue);
+        let res = loop {
+            if unsafe { (*node).state() } != State::PARK {
+                break Ok(());
+            }
+            let interrupted = interruptible && interrupt::is_interrupted();
+            if interrupted || deadline.is_some_and(|end| end <= Instant::now()) {
+                let mut queue = self.queue.lock();
+                if unsafe { (*node).state() } == State::PARK {
+                    queue.remove(node);
+                    unsafe { (*node).set_state(State::CANCELLED) };
+                    if interrupted {
+                        interrupt::interrupted();
+                        break Err(BarrierError::Interrupted);
+                    }
+                    break Err(BarrierError::Timeout);
+                }
+                continue;
+            }
+            self.strategy.wait(backoff, &Parker::new(deadline));
+        };
+        drop(unsafe { Box::from_raw(node) });
+        res
+    }
+
+    /// Runs the action for the last party of `generation` with the barrier unlocked, then
+    /// releases the generation unless the action or another thread broke or reset it.
+    fn trip(&self, generation: usize) -> Result<usize, BarrierError> {
+        let res = match &self.action {
+            Some(action) => panic::catch_unwind(AssertUnwindSafe(action)),
+            None => Ok(()),
+        };
+        let mut queue = self.queue.lock();
+        let intact = !self.broken.load(Ordering::Relaxed) && self.generation.load(Ordering::Relaxed) == generation;
+        if intact {
+            if res.is_ok() {
+                self.next_generation(&mut queue);
+            } else {
+                self.break_barrier(&mut queue);
+            }
+        }
+        drop(queue);
+        match res {
+            Err(err) => panic::resume_unwind(err),
+            Ok(()) if intact => Ok(0),
+            Ok(()) => Err(BarrierError::BrokenBarrier),
+        }
+    }
+
+    /// Releases the current generation and starts the next one.
+    fn next_generation(&self, queue: &mut WaitQueueGuard<'_>) {
+        Self::wake_all(queue, State::ACQUIRED);
+        self.count.store(self.parties, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Marks the barrier broken and wakes the waiting parties with `BrokenBarrier`.
+    fn break_barrier(&self, queue: &mut WaitQueueGuard<'_>) {
+        self.broken.store(true, Ordering::Release);
+        self.count.store(self.parties, Ordering::Relaxed);
+        Self::wake_all(queue, State::RUNNING);
+    }
+
+    fn wake_all(queue: &mut WaitQueueGuard<'_>, state: State) {
+        loop {
+            let first = queue.first();
+            if first.is_null() {
+                break;
+            }
+            queue.wake(first, state);
+        }
+    }
+
+    /// Breaks the current generation, the parties waiting in it get `BrokenBarrier`, and
+    /// starts a fresh unbroken one.
+    pub fn reset(&self) {
+        let mut queue = self.queue.lock();
+        self.break_barrier(&mut queue);
+        self.broken.store(false, Ordering::Release);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Guard that breaks the barrier if this party panics before reaching it.
+    pub fn guard(&self) -> BarrierGuard<'_> {
+        BarrierGuard { barrier: self }
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Acquire)
+    }
+
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+
+    /// Number of completed generations, resets included.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Parties currently waiting at the barrier.
+    pub fn number_waiting(&self) -> usize {
+        let _queue = self.queue.lock();
+        self.parties - self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for BarrierGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let mut queue = self.barrier.queue.lock();
+            self.barrier.break_barrier(&mut queue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CyclicBarrier;
+    use crate::lock::{BarrierError, Interrupter};
+    use std::sync::mpsc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn barrier_generations() {
+        let trips = Arc::new(AtomicUsize::new(0));
+        let barrier = {
+            let trips = trips.clone();
+            Arc::new(CyclicBarrier::with_action(4, move || {
+                trips.fetch_add(1, Ordering::SeqCst);
+            }))
+        };
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let trips = trips.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for step in 0..5 {
+                        if barrier.wait().unwrap() == 0 {
+                            last += 1;
+                        }
+                        // the action ran before anyone was released
+                        assert!(trips.load(Ordering::SeqCst) > step);
+                    }
+                    last
+                })
+            })
+            .collect();
+        let last: usize = handlers.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(last, 5);
+        assert_eq!(trips.load(Ordering::SeqCst), 5);
+        assert_eq!(barrier.generation(), 5);
+        assert_eq!(barrier.number_waiting(), 0);
+    }
+
+    #[test]
+    fn barrier_action_uses_barrier() {
+        static BARRIER: OnceLock<CyclicBarrier> = OnceLock::new();
+        static WAITING: AtomicUsize = AtomicUsize::new(0);
+        let barrier = BARRIER.get_or_init(|| {
+            CyclicBarrier::with_action(3, || {
+                let barrier = BARRIER.get().unwrap();
+                WAITING.store(barrier.number_waiting(), Ordering::SeqCst);
+                // the second generation is reset by its own action
+                if barrier.generation() == 1 {
+                    barrier.reset();
+                }
+            })
+        });
+        for expected in [Ok(()), Err(BarrierError::BrokenBarrier)] {
+            let waiters: Vec<_> = (0..2).map(|_| thread::spawn(|| barrier.wait().map(drop))).collect();
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(barrier.wait().map(drop), expected);
+            waiters.into_iter().for_each(|w| assert_eq!(w.join().unwrap(), expected));
+            assert_eq!(WAITING.load(Ordering::SeqCst), 3);
+        }
+        assert!(!barrier.is_broken());
+        assert_eq!(barrier.generation(), 2);
+        assert_eq!(barrier.number_waiting(), 0);
+    }
+
+    #[test]
+    fn barrier_broken_by_timeout() {
+        let barrier = Arc::new(CyclicBarrier::new(3));
+        let waiter = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(barrier.wait_timeout(Duration::from_millis(20)), Err(BarrierError::Timeout));
+        assert_eq!(waiter.join().unwrap(), Err(BarrierError::BrokenBarrier));
+        assert!(barrier.is_broken());
+        assert_eq!(barrier.wait(), Err(BarrierError::BrokenBarrier));
+        barrier.reset();
+        assert!(!barrier.is_broken());
+    }
+
+    #[test]
+    fn barrier_broken_by_panic() {
+        let barrier = Arc::new(CyclicBarrier::new(2));
+        let failing = {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let _guard = barrier.guard();
+                thread::sleep(Duration::from_millis(20));
+                panic!("party failed");
+            })
+        };
+        assert_eq!(barrier.wait(), Err(BarrierError::BrokenBarrier));
+        assert!(failing.join().is_err());
+    }
+
+    #[test]
+    fn barrier_interrupted() {
+        let barrier = Arc::new(CyclicBarrier::new(3));
+        let (tx, rx) = mpsc::channel();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    tx.send(Interrupter::current()).unwrap();
+                    barrier.wait_interruptibly(None)
+                })
+            })
+            .collect();
+        let interrupter = rx.recv().unwrap();
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        interrupter.interrupt();
+        let mut results: Vec<_> = waiters.into_iter().map(|w| w.join().unwrap()).collect();
+        results.sort_by_key(|res| *res == Err(BarrierError::Interrupted));
+        assert_eq!(results, [Err(BarrierError::BrokenBarrier), Err(BarrierError::Interrupted)]);
+        assert!(barrier.is_broken());
+    }
+
+    #[test]
+    fn barrier_arrival_during_action() {
+        let running = Arc::new(AtomicBool::new(false));
+        let release = Arc::new(AtomicBool::new(false));
+        let barrier = {
+            let (running, release) = (running.clone(), release.clone());
+            Arc::new(CyclicBarrier::with_action(1, move || {
+                running.store(true, Ordering::SeqCst);
+                while !release.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }))
+        };
+        let tripping = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait())
+        };
+        while !running.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert_eq!(barrier.wait_timeout(Duration::from_millis(20)), Err(BarrierError::Timeout));
+        let (tx, rx) = mpsc::channel();
+        let interrupted = {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                tx.send(Interrupter::current()).unwrap();
+                barrier.wait_interruptibly(None)
+            })
+        };
+        rx.recv().unwrap().interrupt();
+        assert_eq!(interrupted.join().unwrap(), Err(BarrierError::Interrupted));
+        release.store(true, Ordering::SeqCst);
+        assert_eq!(tripping.join().unwrap(), Ok(0));
+        assert!(!barrier.is_broken());
+        assert_eq!(barrier.wait_timeout(Duration::MAX), Ok(0));
+        assert_eq!(barrier.generation(), 2);
+    }
+}
@@ -1,96 +1,121 @@
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
-use std::thread::Thread;
-use std::time::Instant;
-use crate::lock::utils::{Node, State};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use crate::lock::error::AcquireError;
+#[cfg(feature = "stats")]
+use crate::lock::stats::LockStats;
+use crate::lock::utils::{Hooks, QueuedSynchronizer, Synchronizer};
+use crate::lock::wait::WaitStrategy;
 
+/// One-shot latch, waiters are released once `count_down` was called `count` times.
 pub struct CountDownLatch{
-    head:AtomicPtr<Node>,
-    tail:AtomicPtr<Node>,
-    count:AtomicUsize
+    // the state holds the remaining count
+    sync:QueuedSynchronizer
+}
+
+/// Calls [`CountDownLatch::count_down`] when dropped, also while unwinding from a panic.
+#[must_use]
+pub struct CountDownGuard<'a>{
+    latch:&'a CountDownLatch
 }
 
 impl CountDownLatch{
-    pub(crate) fn new(count:usize)->Self{
+    /// # Panics
+    ///
+    /// If `count` is zero or more than `i32::MAX`, the count is kept in 32 bits.
+    pub fn new(count:usize)->Self{
         assert!(count>0);
-        let node=Box::into_raw(Box::new(Node::new()));
+        let count=i32::try_from(count).expect("count must fit in an i32");
         Self{
-            head:AtomicPtr::new(node),
-            tail:AtomicPtr::new(node),
-            count:AtomicUsize::new(count)
+            sync:QueuedSynchronizer::new(count,"CountDownLatch")
         }
     }
-    pub(crate) fn count_down(&self){
-        loop {
-            let count=self.count.load(Ordering::Acquire);
-            if count==0{
-                break;
-            }
-            if self.count.compare_exchange(count,count-1,Ordering::SeqCst,Ordering::Relaxed).is_ok(){
-                if count==1{
-                    self.signal_all();
-                }
-                break;
-            }
-        }
+
+    /// Sets how threads wait for the count, by default [`Adaptive`](crate::lock::Adaptive).
+    pub fn with_wait_strategy(mut self,strategy:impl WaitStrategy+'static)->Self{
+        self.sync.set_wait_strategy(strategy);
+        self
     }
 
-    fn signal_all(&self){
-        let mut next=unsafe{(*self.head.load(Ordering::Relaxed)).next};
-        loop {
-            if next.is_null(){
-                break;
-            }
-            unsafe{
-                (*next).thread.unpark();
-                next=(*next).next;
-            };
+    pub fn count_down(&self){
+        Hooks(self).release_shared(1);
+    }
+
+    /// Counts down once the returned guard is dropped.
+    pub fn count_down_guard(&self)->CountDownGuard<'_>{
+        CountDownGuard{latch:self}
+    }
+
+    /// Blocks until the count reaches zero.
+    pub fn wait(&self){
+        let _=Hooks(self).acquire_shared(1,None,false);
+    }
+
+    /// Blocks until the count reaches zero or `timeout` elapses, `Err(Timeout)` in the latter
+    /// case. A timeout too long for an [`Instant`] waits without one.
+    pub fn wait_timeout(&self,timeout:Duration)->Result<(),AcquireError>{
+        Hooks(self).acquire_shared(1,Instant::now().checked_add(timeout),false)
+    }
+
+    /// Blocks until the count reaches zero or `deadline` passes, `Err(Timeout)` in the latter case.
+    pub fn wait_deadline(&self,deadline:Instant)->Result<(),AcquireError>{
+        Hooks(self).acquire_shared(1,Some(deadline),false)
+    }
+
+    /// Blocks until the count reaches zero, `deadline` passes or the thread is interrupted.
+    pub fn wait_interruptibly(&self,deadline:Option<Instant>)->Result<(),AcquireError>{
+        Hooks(self).acquire_shared(1,deadline,true)
+    }
+
+    pub fn available_counts(&self)->usize{
+        self.sync.state().load(Ordering::Relaxed) as usize
+    }
+    #[cfg(feature = "stats")]
+    pub fn stats(&self)->LockStats{
+        self.sync.stats()
+    }
+}
+
+impl Synchronizer for Hooks<'_, CountDownLatch> {
+    fn sync(&self)->&QueuedSynchronizer{
+        &self.sync
+    }
+
+    /// Passes once the count is zero, and so does every waiter after it.
+    fn try_acquire_shared(&self,_arg:isize,_queued:bool)->isize{
+        if self.sync.state().load(Ordering::Acquire)==0{
+            1
+        }else{
+            -1
         }
     }
 
-    pub(crate) fn wait(&self,deadline:Option<Instant>){
-        let node=Box::into_raw(Box::new(Node::new()));
-        let mut enqueue=false;
+    /// True for the count down that reached zero.
+    fn try_release_shared(&self,_arg:isize)->bool{
+        let count=self.sync.state();
         loop {
-            let count=self.count.load(Ordering::Acquire);
-            if count==0{
-                if !enqueue{
-                    drop(unsafe{Box::from_raw(node)});
-                }
-                break;
+            let current=count.load(Ordering::Acquire);
+            if current==0{
+                return false;
             }
-            let tail=self.tail.load(Ordering::Acquire);
-            if !enqueue{
-                if self.tail.compare_exchange(tail,node,Ordering::SeqCst,Ordering::Relaxed).is_ok(){
-                    unsafe{(*tail).next=node};
-                    enqueue=true;
-                }
-            }else {
-                let (timed,end)=if deadline.is_some(){
-                    (true,deadline.unwrap())
-                }else{
-                    (false,Instant::now())
-                };
-                let now=Instant::now();
-                if !timed{
-                    std::thread::park();   
-                }else if end>now{
-                    std::thread::park_timeout(end-now);
-                }else{
-                    break;
-                }
+            if count.compare_exchange(current,current-1,Ordering::SeqCst,Ordering::Relaxed).is_ok(){
+                return current==1;
             }
-
         }
     }
-    pub(crate) fn available_counts(&self)->usize{
-        self.count.load(Ordering::Relaxed)
+}
+
+impl Drop for CountDownGuard<'_>{
+    fn drop(&mut self){
+        self.latch.count_down();
     }
 }
 
 #[cfg(test)]
 mod test{
     use super::CountDownLatch;
-    use std::{sync::Arc, thread, time::Duration};
+    use crate::lock::{AcquireError, Interrupter};
+    use std::sync::mpsc;
+    use std::{sync::Arc, thread, time::{Duration, Instant}};
 
     #[test]
     fn countdown_normal(){
@@ -109,7 +134,7 @@ mod test{
             let count=Arc::clone(&count_down);
             let t=thread::spawn(move ||{
                 println!("await");
-                count.wait(None);
+                count.wait();
                 println!("await complete!");
             });
 
@@ -120,4 +145,54 @@ mod test{
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn countdown_timeout(){
+        let count_down=Arc::new(CountDownLatch::new(1));
+        assert_eq!(count_down.wait_timeout(Duration::from_millis(30)),Err(AcquireError::Timeout));
+        assert!(!count_down.sync.has_queued_threads());
+        let waiter={
+            let count=Arc::clone(&count_down);
+            thread::spawn(move ||count.wait_timeout(Duration::from_secs(5)))
+        };
+        thread::sleep(Duration::from_millis(10));
+        count_down.count_down();
+        assert_eq!(waiter.join().unwrap(),Ok(()));
+        assert_eq!(count_down.wait_deadline(Instant::now()),Ok(()));
+        assert_eq!(count_down.wait_timeout(Duration::MAX),Ok(()));
+    }
+
+    #[test]
+    fn countdown_guard(){
+        let count_down=Arc::new(CountDownLatch::new(3));
+        let workers:Vec<_>=(0..3).map(|i|{
+            let count=Arc::clone(&count_down);
+            thread::spawn(move ||{
+                let _guard=count.count_down_guard();
+                if i==1{
+                    panic!("worker failed");
+                }
+            })
+        }).collect();
+        assert_eq!(count_down.wait_timeout(Duration::from_secs(5)),Ok(()));
+        assert_eq!(workers.into_iter().map(|w|w.join()).filter(Result::is_err).count(),1);
+        assert_eq!(count_down.available_counts(),0);
+    }
+
+    #[test]
+    fn countdown_interrupt(){
+        let count_down=Arc::new(CountDownLatch::new(1));
+        let (tx,rx)=mpsc::channel();
+        let waiter={
+            let count=Arc::clone(&count_down);
+            thread::spawn(move ||{
+                tx.send(Interrupter::current()).unwrap();
+                count.wait_interruptibly(None)
+            })
+        };
+        rx.recv().unwrap().interrupt();
+        assert_eq!(waiter.join().unwrap(),Err(AcquireError::Interrupted));
+        assert!(!count_down.sync.has_queued_threads());
+        count_down.count_down();
+        assert_eq!(count_down.wait_interruptibly(None),Ok(()));
+    }
+}
@@ -0,0 +1,333 @@
+//! Deadlock detection, only with the `deadlock` feature. The primitives report which
+//! threads hold and wait for them into a global wait-for graph, which is searched for a
+//! deadlock whenever a thread is about to wait. Without the feature the tracker is a zero
+//! sized type whose methods compile to nothing.
+//!
+//! Only the locks, which only their holder can release, report holders. Any thread may
+//! release semaphore permits, so waiting for them is never taken as part of a deadlock.
+#[cfg(feature = "deadlock")]
+use std::collections::HashMap;
+#[cfg(feature = "deadlock")]
+use std::fmt::{Display, Formatter};
+#[cfg(feature = "deadlock")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "deadlock")]
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+#[cfg(feature = "deadlock")]
+use crate::lock::reentrant::current_thread_id;
+
+/// Which lock a thread waits for, `kind` is the type of the primitive and `id` tells the
+/// instances apart.
+#[cfg(feature = "deadlock")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockIdentity {
+    pub kind: &'static str,
+    pub id: usize,
+}
+
+/// A thread of a deadlock, waiting for a lock held by other threads of it.
+#[cfg(feature = "deadlock")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockedThread {
+    /// The thread's name, or its id if it has none.
+    pub thread: String,
+    pub waits_for: LockIdentity,
+    pub held_by: Vec<String>,
+}
+
+/// Threads that wait for each other, reported to the [`DeadlockHandler`].
+#[cfg(feature = "deadlock")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlockReport {
+    pub threads: Vec<BlockedThread>,
+}
+
+/// What to do about a detected deadlock. The handler runs on the thread that was about to
+/// wait and closed the cycle.
+#[cfg(feature = "deadlock")]
+#[derive(Clone)]
+pub enum DeadlockHandler {
+    /// Panic on the detecting thread, unwinding releases the locks it holds. The default.
+    Panic,
+    /// Print the report to stderr and keep waiting.
+    Log,
+    /// Call the function and keep waiting, unless it panics.
+    Callback(Arc<dyn Fn(&DeadlockReport) + Send + Sync>),
+}
+
+#[cfg(feature = "deadlock")]
+static HANDLER: Mutex<DeadlockHandler> = Mutex::new(DeadlockHandler::Panic);
+
+#[cfg(feature = "deadlock")]
+static GRAPH: LazyLock<Mutex<Graph>> = LazyLock::new(|| Mutex::new(Graph::default()));
+
+#[cfg(feature = "deadlock")]
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Replaces the handler called for every detected deadlock.
+#[cfg(feature = "deadlock")]
+pub fn set_deadlock_handler(handler: DeadlockHandler) {
+    *HANDLER.lock().unwrap_or_else(PoisonError::into_inner) = handler;
+}
+
+#[cfg(feature = "deadlock")]
+#[derive(Default)]
+struct Graph {
+    // threads holding each lock with their hold counts
+    holders: HashMap<usize, Vec<(usize, usize)>>,
+    waiting: HashMap<usize, LockIdentity>,
+    names: HashMap<usize, String>,
+}
+
+#[cfg(feature = "deadlock")]
+impl Graph {
+    fn name(&self, thread: usize) -> String {
+        self.names.get(&thread).cloned().unwrap_or_else(|| format!("#{thread}"))
+    }
+
+    /// Whether `thread` waits for a lock whose holders are all blocked as well, collecting
+    /// the waits on the way. Threads in `blocked` are taken as blocked, which closes cycles.
+    fn is_blocked(&self, thread: usize, blocked: &mut Vec<usize>, waits: &mut Vec<(usize, LockIdentity)>) -> bool {
+        if blocked.contains(&thread) {
+            return true;
+        }
+        let Some(lock) = self.waiting.get(&thread) else {
+            return false;
+        };
+        let holders = match self.holders.get(&lock.id) {
+            Some(holders) if !holders.is_empty() => holders,
+            // nobody to wait for, e.g. a free lock or a semaphore
+            _ => return false,
+        };
+        let (blocked_len, waits_len) = (blocked.len(), waits.len());
+        blocked.push(thread);
+        waits.push((thread, *lock));
+        if holders.iter().all(|&(holder, _)| self.is_blocked(holder, blocked, waits)) {
+            return true;
+        }
+        blocked.truncate(blocked_len);
+        waits.truncate(waits_len);
+        false
+    }
+
+    fn report(&self, waits: &[(usize, LockIdentity)]) -> DeadlockReport {
+        let threads = waits
+            .iter()
+            .map(|&(thread, lock)| BlockedThread {
+                thread: self.name(thread),
+                waits_for: lock,
+                held_by: self.holders[&lock.id].iter().map(|&(holder, _)| self.name(holder)).collect(),
+            })
+            .collect();
+        DeadlockReport { threads }
+    }
+}
+
+#[cfg(feature = "deadlock")]
+impl Display for LockIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.kind, self.id)
+    }
+}
+
+#[cfg(feature = "deadlock")]
+impl Display for DeadlockReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadlock detected:")?;
+        for blocked in &self.threads {
+            write!(f, "\n  thread '{}' waits for {} held by {:?}", blocked.thread, blocked.waits_for, blocked.held_by)?;
+        }
+        Ok(())
+    }
+}
+
+/// The node of one lock in the wait-for graph.
+#[cfg(feature = "deadlock")]
+pub(crate) struct DeadlockTracker {
+    identity: LockIdentity,
+}
+
+#[cfg(not(feature = "deadlock"))]
+pub(crate) struct DeadlockTracker;
+
+#[cfg(feature = "deadlock")]
+impl DeadlockTracker {
+    pub(crate) fn new(kind: &'static str) -> Self {
+        Self {
+            identity: LockIdentity { kind, id: NEXT_ID.fetch_add(1, Ordering::Relaxed) },
+        }
+    }
+
+    /// The calling thread took `n` more holds. Taking the lock it waits for ends its wait,
+    /// before it gets to [`DeadlockTracker::wait_done`].
+    pub(crate) fn acquired(&self, n: usize) {
+        let thread = current_thread_id();
+        let mut graph = graph();
+        register_name(&mut graph, thread);
+        if graph.waiting.get(&thread).is_some_and(|lock| lock.id == self.identity.id) {
+            graph.waiting.remove(&thread);
+        }
+        let holders = graph.holders.entry(self.identity.id).or_default();
+        match holders.iter_mut().find(|(holder, _)| *holder == thread) {
+            Some((_, holds)) => *holds += n,
+            None => holders.push((thread, n)),
+        }
+    }
+
+    /// The calling thread gave up `n` holds.
+    pub(crate) fn released(&self, n: usize) {
+        let thread = current_thread_id();
+        let mut graph = graph();
+        let Some(holders) = graph.holders.get_mut(&self.identity.id) else {
+            return;
+        };
+        let Some(i) = holders.iter().position(|(holder, _)| *holder == thread) else {
+            return;
+        };
+        holders[i].1 = holders[i].1.saturating_sub(n);
+        if holders[i].1 == 0 {
+            holders.swap_remove(i);
+        }
+        if holders.is_empty() {
+            graph.holders.remove(&self.identity.id);
+        }
+    }
+
+    /// The calling thread is about to wait for the lock without a deadline, a timed wait
+    /// ends by itself. Runs the handler if that deadlocks, which by default panics.
+    pub(crate) fn wait(&self) {
+        let thread = current_thread_id();
+        let report = {
+            let mut graph = graph();
+            register_name(&mut graph, thread);
+            graph.waiting.insert(thread, self.identity);
+            let mut waits = Vec::new();
+            if !graph.is_blocked(thread, &mut Vec::new(), &mut waits) {
+                return;
+            }
+            graph.report(&waits)
+        };
+        let handler = HANDLER.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        match handler {
+            DeadlockHandler::Panic => {
+                self.wait_done();
+                panic!("{report}");
+            }
+            DeadlockHandler::Log => eprintln!("{report}"),
+            DeadlockHandler::Callback(callback) => {
+                // so a panicking callback does not leave the thread waiting in the graph
+                struct Done<'a>(&'a DeadlockTracker);
+                impl Drop for Done<'_> {
+                    fn drop(&mut self) {
+                        if std::thread::panicking() {
+                            self.0.wait_done();
+                        }
+                    }
+                }
+                let _done = Done(self);
+                callback(&report);
+            }
+        }
+    }
+
+    /// The calling thread stopped waiting, with or without the lock.
+    pub(crate) fn wait_done(&self) {
+        graph().waiting.remove(&current_thread_id());
+    }
+}
+
+#[cfg(feature = "deadlock")]
+fn graph() -> std::sync::MutexGuard<'static, Graph> {
+    GRAPH.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(feature = "deadlock")]
+fn register_name(graph: &mut Graph, thread: usize) {
+    graph
+        .names
+        .entry(thread)
+        .or_insert_with(|| std::thread::current().name().map_or_else(|| format!("#{thread}"), str::to_owned));
+}
+
+#[cfg(not(feature = "deadlock"))]
+impl DeadlockTracker {
+    pub(crate) const fn new(_kind: &'static str) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub(crate) fn acquired(&self, _n: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn released(&self, _n: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn wait(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn wait_done(&self) {}
+}
+
+#[cfg(all(test, feature = "deadlock"))]
+mod test {
+    use super::{set_deadlock_handler, DeadlockHandler, DeadlockReport};
+    use crate::lock::{ReentrantLock, Semaphore};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn deadlock_detected() {
+        let reports = Arc::new(Mutex::new(Vec::<DeadlockReport>::new()));
+        {
+            let reports = reports.clone();
+            set_deadlock_handler(DeadlockHandler::Callback(Arc::new(move |report| {
+                reports.lock().unwrap().push(report.clone());
+                panic!("{report}");
+            })));
+        }
+        // created at one place, so lock order validation takes them as one class
+        let new_lock = || ReentrantLock::new((), false);
+        let locks = Arc::new((new_lock(), new_lock()));
+        let barrier = Arc::new(Barrier::new(2));
+        let handlers: Vec<_> = ["first", "second"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let (locks, barrier) = (locks.clone(), barrier.clone());
+                thread::Builder::new()
+                    .name(name.to_owned())
+                    .spawn(move || {
+                        let (mine, theirs) = if i == 0 { (&locks.0, &locks.1) } else { (&locks.1, &locks.0) };
+                        let _mine = mine.lock();
+                        barrier.wait();
+                        let _theirs = theirs.lock();
+                    })
+                    .unwrap()
+            })
+            .collect();
+        // the thread closing the cycle panicked and let the other one through
+        assert_eq!(handlers.into_iter().map(|h| h.join()).filter(Result::is_err).count(), 1);
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        let mut threads: Vec<_> = reports[0].threads.iter().map(|t| (t.thread.as_str(), t.held_by.clone())).collect();
+        threads.sort();
+        assert_eq!(threads, [("first", vec!["second".to_owned()]), ("second", vec!["first".to_owned()])]);
+        assert!(reports[0].threads.iter().all(|t| t.waits_for.kind == "ReentrantLock"));
+
+        set_deadlock_handler(DeadlockHandler::Panic);
+        // waiting for permits only the waiting thread holds is no deadlock, any other
+        // thread may add some
+        let semaphore = Semaphore::new(1, false);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let _permit = semaphore.acquire(1, None).unwrap();
+                let _more = semaphore.acquire(1, None).unwrap();
+            });
+            thread::sleep(Duration::from_millis(20));
+            semaphore.add_permits(1);
+            waiter.join().unwrap();
+        });
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Why a blocking acquisition returned without the resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireError {
+    /// The deadline passed before the resource became available.
+    Timeout,
+    /// The primitive was closed while waiting or before the call.
+    Closed,
+    /// The waiting thread was interrupted.
+    Interrupted,
+}
+
+impl Display for AcquireError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AcquireError::Timeout => write!(f, "timed out while waiting"),
+            AcquireError::Closed => write!(f, "closed while waiting"),
+            AcquireError::Interrupted => write!(f, "interrupted while waiting"),
+        }
+    }
+}
+
+impl Error for AcquireError {}
+
+/// Why waiting on a [`CyclicBarrier`](crate::lock::CyclicBarrier) failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarrierError {
+    /// Another party timed out, panicked or reset the barrier while this one waited,
+    /// or the barrier was already broken.
+    BrokenBarrier,
+    /// This party's own timeout elapsed, the barrier is broken for the others.
+    Timeout,
+    /// This party was interrupted, the barrier is broken for the others.
+    Interrupted,
+}
+
+impl Display for BarrierError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BarrierError::BrokenBarrier => write!(f, "barrier is broken"),
+            BarrierError::Timeout => write!(f, "timed out while waiting at the barrier"),
+            BarrierError::Interrupted => write!(f, "interrupted while waiting at the barrier"),
+        }
+    }
+}
+
+impl Error for BarrierError {}
+
+/// Returned by [`Exchanger::exchange`](crate::lock::Exchanger::exchange) when no partner
+/// arrived, hands the offered value back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExchangeError<T> {
+    pub reason: AcquireError,
+    pub value: T,
+}
+
+impl<T> Display for ExchangeError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no exchange partner: {}", self.reason)
+    }
+}
+
+impl<T: Debug> Error for ExchangeError<T> {}
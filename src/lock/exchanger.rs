@@ -0,0 +1,273 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use crate::lock::error::{AcquireError, ExchangeError};
+use crate::lock::interrupt;
+use crate::lock::utils::{Backoff, Node, State};
+use crate::lock::wait::{Parker, SpinThenPark, WaitStrategy};
+
+/// A value waiting in a slot for a partner.
+struct Offer<T> {
+    node: Node,
+    item: UnsafeCell<Option<T>>,
+    // filled by the partner before it marks `node` acquired
+    hole: UnsafeCell<Option<T>>,
+}
+
+enum Wait<T> {
+    Matched(T),
+    // gave up an arena slot that nobody came to
+    Withdrawn,
+    Failed(AcquireError),
+}
+
+/// Rendezvous where two threads swap values.
+///
+/// Without contention all exchanges go through slot 0. Threads that collide on it spread
+/// over an elimination arena of further slots, where they only spin for a while before
+/// moving back towards slot 0, the only slot waiters park on.
+pub struct Exchanger<T> {
+    slots: Box<[AtomicPtr<Offer<T>>]>,
+    // highest arena slot in use
+    bound: AtomicUsize,
+    strategy: Box<dyn WaitStrategy>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for Exchanger<T> {}
+
+impl<T> Exchanger<T> {
+    pub fn new() -> Self {
+        let parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_arena(parallelism.div_ceil(2))
+    }
+
+    /// Creates an exchanger with `slots` slots, including the main one.
+    pub fn with_arena(slots: usize) -> Self {
+        assert!(slots > 0);
+        Self {
+            slots: (0..slots).map(|_| AtomicPtr::new(std::ptr::null_mut())).collect(),
+            bound: AtomicUsize::new(0),
+            strategy: Box::new(SpinThenPark),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets how a thread waits on the main slot once it spun for a partner, by default
+    /// [`SpinThenPark`].
+    pub fn with_wait_strategy(self, strategy: impl WaitStrategy + 'static) -> Self {
+        Self {
+            strategy: Box::new(strategy),
+            ..self
+        }
+    }
+
+    /// Waits for another thread to call `exchange` and returns its value. If `deadline`
+    /// passes first, the own value comes back in the error.
+    pub fn exchange(&self, value: T, deadline: Option<Instant>) -> Result<T, ExchangeError<T>> {
+        self.exchange_value(value, deadline, false)
+    }
+
+    /// Same as [`Exchanger::exchange`], but gives the value back with `Interrupted` once
+    /// the thread is interrupted.
+    pub fn exchange_interruptibly(&self, value: T, deadline: Option<Instant>) -> Result<T, ExchangeError<T>> {
+        if interrupt::interrupted() {
+            return Err(ExchangeError { reason: AcquireError::Interrupted, value });
+        }
+        self.exchange_value(value, deadline, true)
+    }
+
+    fn exchange_value(&self, value: T, deadline: Option<Instant>, interruptible: bool) -> Result<T, ExchangeError<T>> {
+        let offer = Box::into_raw(Box::new(Offer {
+            node: Node::new(0),
+            item: UnsafeCell::new(Some(value)),
+            hole: UnsafeCell::new(None),
+        }));
+        let mut index = 0;
+        let mut collided = false;
+        let res = loop {
+            if collided {
+                let reason = if interruptible && interrupt::interrupted() {
+                    Some(AcquireError::Interrupted)
+                } else {
+                    deadline.is_some_and(|end| end <= Instant::now()).then_some(AcquireError::Timeout)
+                };
+                if let Some(reason) = reason {
+                    let value = unsafe { (*(*offer).item.get()).take().unwrap() };
+                    break Err(ExchangeError { reason, value });
+                }
+            }
+            let slot = &self.slots[index];
+            let current = slot.load(Ordering::Acquire);
+            if !current.is_null() {
+                if slot.compare_exchange(current, std::ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    break Ok(unsafe { Self::complete(current, (*(*offer).item.get()).take()) });
+                }
+                index = self.collide();
+                collided = true;
+                continue;
+            }
+            unsafe { (*offer).node.set_state(State::PARK) };
+            if slot.compare_exchange(current, offer, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                index = self.collide();
+                collided = true;
+                continue;
+            }
+            match self.await_match(offer, index, deadline, interruptible) {
+                Wait::Matched(value) => break Ok(value),
+                Wait::Withdrawn => index = self.shrink(index),
+                Wait::Failed(reason) => {
+                    let value = unsafe { (*(*offer).item.get()).take().unwrap() };
+                    break Err(ExchangeError { reason, value });
+                }
+            }
+        };
+        drop(unsafe { Box::from_raw(offer) });
+        res
+    }
+
+    /// Swaps with the waiting `partner` that was just taken out of its slot.
+    unsafe fn complete(partner: *mut Offer<T>, value: Option<T>) -> T {
+        let theirs = (*(*partner).item.get()).take().unwrap();
+        *(*partner).hole.get() = value;
+        let waiter = (*partner).node.waiter.clone();
+        // the partner may free its offer as soon as it sees this
+        (*partner).node.set_state(State::ACQUIRED);
+        waiter.wake();
+        theirs
+    }
+
+    fn await_match(&self, offer: *mut Offer<T>, index: usize, deadline: Option<Instant>, interruptible: bool) -> Wait<T> {
+        let backoff = Backoff::new();
+        loop {
+            if unsafe { (*offer).node.state() } == State::ACQUIRED {
+                return Wait::Matched(unsafe { (*(*offer).hole.get()).take().unwrap() });
+            }
+            let interrupted = interruptible && interrupt::is_interrupted();
+            let expired = interrupted || deadline.is_some_and(|end| end <= Instant::now());
+            if !backoff.is_complete() && !expired {
+                backoff.spin_heavy();
+                continue;
+            }
+            if index != 0 || expired {
+                // a failed withdrawal means a partner took the offer and is completing it
+                if self.slots[index]
+                    .compare_exchange(offer, std::ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    if interrupted {
+                        interrupt::interrupted();
+                        return Wait::Failed(AcquireError::Interrupted);
+                    }
+                    return if expired { Wait::Failed(AcquireError::Timeout) } else { Wait::Withdrawn };
+                }
+                backoff.spin_light();
+                continue;
+            }
+            self.strategy.wait(&backoff, &Parker::new(deadline));
+        }
+    }
+
+    /// Picks an arena slot after a collision, widening the arena if it can.
+    fn collide(&self) -> usize {
+        let bound = self.bound.load(Ordering::Relaxed);
+        let bound = if bound + 1 < self.slots.len() {
+            match self.bound.compare_exchange(bound, bound + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => bound + 1,
+                Err(actual) => actual,
+            }
+        } else {
+            bound
+        };
+        rand::random::<usize>() % (bound + 1)
+    }
+
+    /// Moves one slot towards the main one after nobody showed up at `index`.
+    fn shrink(&self, index: usize) -> usize {
+        let _ = self.bound.compare_exchange(index, index - 1, Ordering::Relaxed, Ordering::Relaxed);
+        index - 1
+    }
+}
+
+impl<T> Default for Exchanger<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Exchanger;
+    use crate::lock::AcquireError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{sync::Arc, thread, time::{Duration, Instant}};
+
+    #[test]
+    fn exchanger_pair() {
+        let exchanger = Arc::new(Exchanger::new());
+        let consumer = {
+            let exchanger = exchanger.clone();
+            thread::spawn(move || {
+                let mut buffer = Vec::new();
+                for i in 0..100 {
+                    buffer = exchanger.exchange(buffer, None).unwrap();
+                    assert_eq!(buffer, vec![i]);
+                    buffer.clear();
+                }
+            })
+        };
+        let mut buffer = Vec::new();
+        for i in 0..100 {
+            buffer.push(i);
+            buffer = exchanger.exchange(buffer, None).unwrap();
+            assert!(buffer.is_empty());
+        }
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn exchanger_timeout() {
+        let exchanger = Exchanger::with_arena(1);
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let err = exchanger.exchange(String::from("lonely"), Some(deadline)).unwrap_err();
+        assert_eq!(err.reason, AcquireError::Timeout);
+        assert_eq!(err.value, "lonely");
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn exchanger_arena() {
+        let exchanger = Arc::new(Exchanger::with_arena(4));
+        let completed = Arc::new(AtomicUsize::new(0));
+        let handlers: Vec<_> = (0..8usize)
+            .map(|id| {
+                let exchanger = exchanger.clone();
+                let completed = completed.clone();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    while completed.load(Ordering::SeqCst) < 2000 {
+                        let deadline = Instant::now() + Duration::from_millis(10);
+                        if let Ok(partner) = exchanger.exchange(id, Some(deadline)) {
+                            received.push(partner);
+                            completed.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                    received
+                })
+            })
+            .collect();
+        let mut given = [0; 8];
+        let mut received = [0; 8];
+        for (id, handler) in handlers.into_iter().enumerate() {
+            for partner in handler.join().unwrap() {
+                assert_ne!(partner, id);
+                given[id] += 1;
+                received[partner] += 1;
+            }
+        }
+        // every value a thread handed out was received exactly once
+        assert_eq!(given, received);
+    }
+}
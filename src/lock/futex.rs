@@ -0,0 +1,122 @@
+//! Sleeping on the state word of a synchronizer with Linux futexes, only with the `futex`
+//! feature on Linux. Every waiter of a word sleeps on one of 32 wake bits, picked by its
+//! thread, so waking one waiter only disturbs the few others that share its bit.
+//!
+//! The futex is the high half of the 64-bit state word, the hooks own the low half. A
+//! wake-up has to change the word a sleeper waits on, or one about to sleep would miss it,
+//! and a state the hooks are free to leave unchanged can't tell it. So the high half
+//! counts the wake-ups, and an uncontended acquisition still takes one atomic operation on
+//! one word. The queue of waiters stays as it is on every platform, fairness, timeouts and
+//! futures rely on it, the feature only changes how a queued thread sleeps.
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::Instant;
+use crate::lock::reentrant::current_thread_id;
+
+/// The wake-up half of `word`.
+fn futex(word: &AtomicU64) -> *const u32 {
+    let offset = if cfg!(target_endian = "little") { 1 } else { 0 };
+    unsafe { word.as_ptr().cast::<u32>().add(offset) }
+}
+
+/// Wake-ups counted on `word` so far, to sleep on.
+pub(crate) fn wakeups(word: &AtomicU64) -> u32 {
+    (word.load(Ordering::Relaxed) >> 32) as u32
+}
+
+/// The wake bit of the calling thread, as a bit set.
+pub(crate) fn bit() -> u32 {
+    1 << (current_thread_id() % 32)
+}
+
+/// Sleeps while the wake-ups of `word` are at `expected`, until woken through a bit of
+/// `bits` or `deadline` passes. Returns early on signals and on wake-ups counted already.
+pub(crate) fn wait(word: &AtomicU64, expected: u32, bits: u32, deadline: Option<Instant>) {
+    // the timeout of FUTEX_WAIT_BITSET is an absolute CLOCK_MONOTONIC time
+    let timeout = deadline.map(|end| {
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let left = end.saturating_duration_since(Instant::now());
+        let nanos = now.tv_nsec as u64 + u64::from(left.subsec_nanos());
+        libc::timespec {
+            tv_sec: now.tv_sec.saturating_add((left.as_secs() + nanos / 1_000_000_000).min(i64::MAX as u64) as libc::time_t),
+            tv_nsec: (nanos % 1_000_000_000) as libc::c_long,
+        }
+    });
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex(word),
+            libc::FUTEX_WAIT_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            timeout.as_ref().map_or(std::ptr::null(), |t| t as *const libc::timespec),
+            std::ptr::null::<u32>(),
+            bits,
+        );
+    }
+}
+
+/// Wakes the waiters sleeping on `word` through a bit of `bits` and keeps every waiter
+/// that read its wake-ups before from going to sleep. Returns how many were woken.
+pub(crate) fn wake(word: &AtomicU64, bits: u32) -> usize {
+    // pairs with the fence in `Parker::futex`: either the waiter sees the wake-up it
+    // checks for, or the wake-ups changed since it read them
+    fence(Ordering::SeqCst);
+    word.fetch_add(1 << 32, Ordering::Relaxed);
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex(word),
+            libc::FUTEX_WAKE_BITSET | libc::FUTEX_PRIVATE_FLAG,
+            i32::MAX,
+            std::ptr::null::<libc::timespec>(),
+            std::ptr::null::<u32>(),
+            bits,
+        ) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bit, wait, wake, wakeups};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::{mpsc, Arc};
+    use std::time::{Duration, Instant};
+    use std::{fs, thread};
+
+    /// Whether thread `tid` of this process is blocked in a futex call.
+    fn in_futex(tid: libc::pid_t) -> bool {
+        let syscall = fs::read_to_string(format!("/proc/self/task/{tid}/syscall")).unwrap();
+        syscall.split(' ').next() == Some(libc::SYS_futex.to_string().as_str())
+    }
+
+    #[test]
+    fn futex_wait_wake() {
+        // the state half is not what sleepers wait on
+        let word = Arc::new(AtomicU64::new(u64::from(u32::MAX)));
+        // wake-ups counted already keep from sleeping, a timed wait ends by itself
+        wait(&word, 1, bit(), None);
+        let start = Instant::now();
+        wait(&word, 0, bit(), Some(start + Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let sleeper_bit = bit().rotate_left(1);
+        let (tx, rx) = mpsc::channel();
+        let sleeper = {
+            let word = word.clone();
+            thread::spawn(move || {
+                tx.send(unsafe { libc::gettid() }).unwrap();
+                // no spurious wake-ups to rule out, nothing else wakes the word
+                wait(&word, 0, sleeper_bit, None);
+                assert_eq!(wakeups(&word), 2);
+            })
+        };
+        let tid = rx.recv().unwrap();
+        while !in_futex(tid) {
+            thread::yield_now();
+        }
+        // only the sleeper's bit wakes it, the word stays changed after the others
+        assert_eq!(wake(&word, sleeper_bit.rotate_left(1)), 0);
+        assert_eq!(wake(&word, sleeper_bit), 1);
+        sleeper.join().unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use std::sync::{Mutex, PoisonError};
+use std::thread::{self, Thread};
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use crate::lock::futex;
+
+thread_local! {
+    static INTERRUPTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+#[cfg(all(feature = "futex", target_os = "linux"))]
+thread_local! {
+    // address of the state word the thread sleeps on in an interruptible wait, 0 if none,
+    // locked by an interrupt so the word outlives the wake-up
+    static FUTEX: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
+}
+
+/// Handle to interrupt a thread, taken on that thread with [`Interrupter::current`].
+///
+/// An interrupt sets the thread's flag and unparks it. The `*_interruptibly` waits of the
+/// lock primitives (and the awaits of a [`Condition`](crate::lock::Condition)) check the
+/// flag when they start and whenever they wake up, leave the wait queue and return
+/// `Interrupted`, clearing the flag. Other waits ignore it, the flag stays set until an
+/// interruptible wait or [`interrupted`] consumes it.
+#[derive(Clone)]
+pub struct Interrupter {
+    flag: Arc<AtomicBool>,
+    thread: Thread,
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    futex: Arc<Mutex<usize>>,
+}
+
+impl Interrupter {
+    /// Handle of the calling thread.
+    pub fn current() -> Self {
+        Self {
+            flag: INTERRUPTED.with(|flag| flag.clone()),
+            thread: thread::current(),
+            #[cfg(all(feature = "futex", target_os = "linux"))]
+            futex: FUTEX.with(|futex| futex.clone()),
+        }
+    }
+
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+        #[cfg(all(feature = "futex", target_os = "linux"))]
+        {
+            let word = self.futex.lock().unwrap_or_else(PoisonError::into_inner);
+            if *word != 0 {
+                futex::wake(unsafe { &*(*word as *const AtomicU64) }, u32::MAX);
+            }
+        }
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+}
+
+/// Lets an interrupt wake the calling thread while it sleeps on `word`, until called again
+/// with `None`. Call it before checking the flag for the last time.
+#[cfg(all(feature = "futex", target_os = "linux"))]
+pub(crate) fn sleep_on(word: Option<&AtomicU64>) {
+    let address = word.map_or(0, |word| word as *const AtomicU64 as usize);
+    FUTEX.with(|futex| *futex.lock().unwrap_or_else(PoisonError::into_inner) = address);
+}
+
+/// Clears the interrupt flag of the calling thread, returns whether it was set.
+pub fn interrupted() -> bool {
+    INTERRUPTED.with(|flag| flag.swap(false, Ordering::SeqCst))
+}
+
+/// Whether the calling thread has been interrupted, leaves the flag as it is.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.with(|flag| flag.load(Ordering::SeqCst))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interrupted, is_interrupted, Interrupter};
+    use std::thread;
+
+    #[test]
+    fn interrupt_flag() {
+        let interrupter = Interrupter::current();
+        assert!(!is_interrupted());
+        interrupter.interrupt();
+        // another thread's flag is untouched
+        assert!(!thread::spawn(is_interrupted).join().unwrap());
+        assert!(interrupter.is_interrupted() && is_interrupted());
+        assert!(interrupted());
+        assert!(!interrupted());
+        assert!(!interrupter.is_interrupted());
+    }
+}
@@ -0,0 +1,299 @@
+//! Lock order validation, only with the `lockdep` feature. Every lock belongs to the class
+//! of the place it was created at. Acquiring a lock while holding others records that
+//! their classes come first, and an acquisition in the opposite order of one seen before is
+//! reported, whether or not the two orders ever met in a deadlock. Without the feature the
+//! tracker is a zero sized type whose methods compile to nothing.
+#[cfg(feature = "lockdep")]
+use std::collections::hash_map::Entry;
+#[cfg(feature = "lockdep")]
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "lockdep")]
+use std::fmt::{Display, Formatter};
+#[cfg(feature = "lockdep")]
+use std::panic::Location;
+#[cfg(feature = "lockdep")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "lockdep")]
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+#[cfg(feature = "lockdep")]
+use crate::lock::reentrant::current_thread_id;
+
+/// The locks of one type created at the same place.
+#[cfg(feature = "lockdep")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockClass {
+    pub kind: &'static str,
+    pub location: &'static Location<'static>,
+}
+
+/// An acquisition of `acquiring` while holding `held`, when `acquiring` was seen held
+/// while acquiring `held` before. `order` is that earlier chain of classes, from
+/// `acquiring` to `held`.
+#[cfg(feature = "lockdep")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOrderViolation {
+    pub thread: String,
+    pub held: LockClass,
+    pub acquiring: LockClass,
+    pub order: Vec<LockClass>,
+}
+
+/// What to do about an inverted lock order. The handler runs on the acquiring thread
+/// before it waits, once for every pair of classes.
+#[cfg(feature = "lockdep")]
+#[derive(Clone)]
+pub enum LockOrderHandler {
+    /// Panic on the acquiring thread. The default.
+    Panic,
+    /// Print the violation to stderr and go on with the acquisition.
+    Log,
+    /// Call the function and go on with the acquisition, unless it panics.
+    Callback(Arc<dyn Fn(&LockOrderViolation) + Send + Sync>),
+}
+
+#[cfg(feature = "lockdep")]
+static HANDLER: Mutex<LockOrderHandler> = Mutex::new(LockOrderHandler::Panic);
+
+#[cfg(feature = "lockdep")]
+static GRAPH: LazyLock<Mutex<Graph>> = LazyLock::new(|| Mutex::new(Graph::default()));
+
+#[cfg(feature = "lockdep")]
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Replaces the handler called for every inverted lock order.
+#[cfg(feature = "lockdep")]
+pub fn set_lock_order_handler(handler: LockOrderHandler) {
+    *HANDLER.lock().unwrap_or_else(PoisonError::into_inner) = handler;
+}
+
+#[cfg(feature = "lockdep")]
+#[derive(Default)]
+struct Graph {
+    // classes seen acquired while holding the key
+    after: HashMap<LockClass, HashSet<LockClass>>,
+    // locks each thread holds, as (lock id, class, hold count)
+    held: HashMap<usize, Vec<(usize, LockClass, usize)>>,
+    reported: HashSet<(LockClass, LockClass)>,
+}
+
+#[cfg(feature = "lockdep")]
+impl Graph {
+    /// The classes from `from` to `to` along the recorded orders, if there is such a chain.
+    fn path(&self, from: LockClass, to: LockClass) -> Option<Vec<LockClass>> {
+        let mut previous = HashMap::from([(from, from)]);
+        let mut pending = VecDeque::from([from]);
+        while let Some(class) = pending.pop_front() {
+            if class == to {
+                let mut path = vec![to];
+                while *path.last().unwrap() != from {
+                    path.push(previous[path.last().unwrap()]);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &next in self.after.get(&class).into_iter().flatten() {
+                if let Entry::Vacant(entry) = previous.entry(next) {
+                    entry.insert(class);
+                    pending.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl Display for LockClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} created at {}", self.kind, self.location)
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl Display for LockOrderViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "inconsistent lock order: thread '{}' acquires {} while holding {}, seen the other way round as:", self.thread, self.acquiring, self.held)?;
+        for class in &self.order {
+            write!(f, "\n  {class}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The class and identity of one lock.
+#[cfg(feature = "lockdep")]
+pub(crate) struct LockdepTracker {
+    id: usize,
+    class: LockClass,
+}
+
+#[cfg(not(feature = "lockdep"))]
+pub(crate) struct LockdepTracker;
+
+#[cfg(feature = "lockdep")]
+impl LockdepTracker {
+    /// The class is the caller's location, so constructors pass on theirs with `#[track_caller]`.
+    #[track_caller]
+    pub(crate) fn new(kind: &'static str) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            class: LockClass { kind, location: Location::caller() },
+        }
+    }
+
+    /// The calling thread is about to block for the lock. Records the order against the
+    /// locks it holds and runs the handler if that inverts a recorded one. Locks of the
+    /// same class are not ordered against each other.
+    pub(crate) fn acquiring(&self) {
+        let thread = current_thread_id();
+        let violations: Vec<_> = {
+            let mut graph = graph();
+            let graph = &mut *graph;
+            let held = graph.held.get(&thread).map_or(&[][..], Vec::as_slice);
+            if held.iter().any(|(id, ..)| *id == self.id) {
+                // taken again by a holder
+                return;
+            }
+            let mut violations = Vec::new();
+            for &(_, class, _) in held {
+                if class == self.class || graph.after.get(&class).is_some_and(|after| after.contains(&self.class)) {
+                    continue;
+                }
+                match graph.path(self.class, class) {
+                    Some(order) => {
+                        if graph.reported.insert((class, self.class)) {
+                            violations.push(LockOrderViolation {
+                                thread: std::thread::current().name().map_or_else(|| format!("#{thread}"), str::to_owned),
+                                held: class,
+                                acquiring: self.class,
+                                order,
+                            });
+                        }
+                    }
+                    None => {
+                        graph.after.entry(class).or_default().insert(self.class);
+                    }
+                }
+            }
+            violations
+        };
+        if violations.is_empty() {
+            return;
+        }
+        let handler = HANDLER.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        for violation in &violations {
+            match &handler {
+                LockOrderHandler::Panic => panic!("{violation}"),
+                LockOrderHandler::Log => eprintln!("{violation}"),
+                LockOrderHandler::Callback(callback) => callback(violation),
+            }
+        }
+    }
+
+    /// The calling thread took `n` more holds.
+    pub(crate) fn acquired(&self, n: usize) {
+        let mut graph = graph();
+        let held = graph.held.entry(current_thread_id()).or_default();
+        match held.iter_mut().find(|(id, ..)| *id == self.id) {
+            Some((.., holds)) => *holds += n,
+            None => held.push((self.id, self.class, n)),
+        }
+    }
+
+    /// `n` holds were given up, their holder no longer orders its next acquisitions after
+    /// this lock. A thread without holds of its own returns permits another thread took,
+    /// which are then taken off that thread.
+    pub(crate) fn released(&self, n: usize) {
+        let thread = current_thread_id();
+        let mut graph = graph();
+        let holder = if graph.held.get(&thread).is_some_and(|held| held.iter().any(|(id, ..)| *id == self.id)) {
+            thread
+        } else {
+            match graph.held.iter().find(|(_, held)| held.iter().any(|(id, ..)| *id == self.id)) {
+                Some((&holder, _)) => holder,
+                None => return,
+            }
+        };
+        let held = graph.held.get_mut(&holder).unwrap();
+        let i = held.iter().position(|(id, ..)| *id == self.id).unwrap();
+        held[i].2 = held[i].2.saturating_sub(n);
+        if held[i].2 == 0 {
+            held.remove(i);
+        }
+        if held.is_empty() {
+            graph.held.remove(&holder);
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+fn graph() -> std::sync::MutexGuard<'static, Graph> {
+    GRAPH.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(not(feature = "lockdep"))]
+impl LockdepTracker {
+    pub(crate) const fn new(_kind: &'static str) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub(crate) fn acquiring(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn acquired(&self, _n: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn released(&self, _n: usize) {}
+}
+
+#[cfg(all(test, feature = "lockdep"))]
+mod test {
+    use super::{set_lock_order_handler, LockOrderHandler, LockOrderViolation};
+    use crate::lock::{ReentrantLock, ReentrantReadWriteLock, Semaphore};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn lockdep_inverted_order() {
+        let violations = Arc::new(Mutex::new(Vec::<LockOrderViolation>::new()));
+        {
+            let violations = violations.clone();
+            set_lock_order_handler(LockOrderHandler::Callback(Arc::new(move |violation| {
+                violations.lock().unwrap().push(violation.clone());
+            })));
+        }
+        let lock = ReentrantLock::new((), false);
+        let semaphore = Semaphore::new(1, false);
+        let rwlock = ReentrantReadWriteLock::new((), false);
+        // lock -> semaphore -> rwlock, taking the lock again on the way is no new order
+        let guard = lock.lock();
+        let permit = semaphore.acquire(1, None).unwrap();
+        drop(lock.lock());
+        drop(guard);
+        drop(rwlock.read());
+        drop(permit);
+        let ours = |violations: &[LockOrderViolation]| {
+            violations.iter().filter(|v| v.acquiring.location.file() == file!()).cloned().collect::<Vec<_>>()
+        };
+        assert!(ours(&violations.lock().unwrap()).is_empty());
+
+        // only reversed through the semaphore, still reported without any deadlock
+        {
+            let _write = rwlock.write();
+            let _guard = lock.lock();
+        }
+        let reported = ours(&violations.lock().unwrap());
+        assert_eq!(reported.len(), 1);
+        assert_eq!((reported[0].held.kind, reported[0].acquiring.kind), ("ReentrantReadWriteLock", "ReentrantLock"));
+        let order: Vec<_> = reported[0].order.iter().map(|class| class.kind).collect();
+        assert_eq!(order, ["ReentrantLock", "Semaphore", "ReentrantReadWriteLock"]);
+
+        // a pair is reported once
+        {
+            let _write = rwlock.write();
+            let _guard = lock.lock();
+        }
+        assert_eq!(ours(&violations.lock().unwrap()).len(), 1);
+        set_lock_order_handler(LockOrderHandler::Panic);
+    }
+}
@@ -1,9 +1,43 @@
-use std::ops::Deref;
-
 mod reentrant;
+mod deadlock;
 mod semaphore;
 mod utils;
 mod countdown;
+mod error;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+mod futex;
+mod stats;
+mod rate_limiter;
+mod barrier;
+mod phaser;
+mod exchanger;
+mod interrupt;
+mod lockdep;
+mod parking_lot;
+mod rwlock;
+mod stamped;
+mod wait;
+
+pub use barrier::{BarrierGuard, CyclicBarrier};
+pub use countdown::{CountDownGuard, CountDownLatch};
+#[cfg(feature = "deadlock")]
+pub use deadlock::{set_deadlock_handler, BlockedThread, DeadlockHandler, DeadlockReport, LockIdentity};
+pub use error::{AcquireError, BarrierError, ExchangeError};
+pub use exchanger::Exchanger;
+pub use interrupt::{interrupted, is_interrupted, Interrupter};
+#[cfg(feature = "lockdep")]
+pub use lockdep::{set_lock_order_handler, LockClass, LockOrderHandler, LockOrderViolation};
+#[cfg(feature = "stats")]
+pub use stats::{LockStats, WAIT_BUCKETS};
+pub use parking_lot::{park, unpark_all, unpark_one, ParkResult, UnparkResult, UnparkToken};
+pub use phaser::Phaser;
+pub use rate_limiter::RateLimiter;
+pub use reentrant::{Condition, ConditionGuard, ReentrantLock, ReentrantLockGuard};
+pub use rwlock::{ReadLockGuard, ReentrantReadWriteLock, WriteLockGuard};
+pub use semaphore::{Acquire, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+pub use stamped::{Stamp, StampedLock};
+pub use utils::{Backoff, QueuedSynchronizer, StateWord, Synchronizer};
+pub use wait::{Adaptive, Park, Parker, Spin, SpinThenPark, SpinThenYield, WaitStrategy};
 
 pub fn semaphore(){
 
@@ -16,34 +50,3 @@ pub fn reentrant_lock(){
 pub fn count_down_latch(){
 
 }
-
-enum Lock{
-    Semaphore(usize),
-    ReentrantLock,
-    CountDownLatch(usize)
-}
-
-
-struct Counter{
-    inner:Lock
-}
-
-impl Counter{
-
-}
-
-impl Deref for Counter{
-    type Target = Lock;
-
-    fn deref(&self) -> &Self::Target {
-        todo!()
-    }
-}
-
-impl Clone for Counter{
-    fn clone(&self) -> Self {
-        todo!()
-    }
-}
-
-
@@ -0,0 +1,279 @@
+//! Global table of wait queues keyed by address, in the manner of the `parking_lot` crate.
+//! A primitive parks its waiters under its own address instead of keeping a queue of its
+//! own, the queues of all primitives that hash to a bucket share it. Primitives can't
+//! move while they have waiters, as the waiters borrow them, so the address is stable for
+//! as long as it matters.
+//!
+//! The keys of [`park`] and the unparks are a space of their own, kept in a table apart
+//! from the crate's primitives. Parking or unparking on the address of one of them leaves
+//! its waiters alone.
+use std::mem;
+use std::thread;
+use std::time::Instant;
+use crate::lock::utils::{Node, State, WaitQueue, WaitQueueGuard};
+
+const BUCKET_BITS: u32 = 8;
+
+static BUCKETS: [WaitQueue; 1 << BUCKET_BITS] = [const { WaitQueue::new() }; 1 << BUCKET_BITS];
+
+static USER_BUCKETS: [WaitQueue; 1 << BUCKET_BITS] = [const { WaitQueue::new() }; 1 << BUCKET_BITS];
+
+/// Handed from an unparking thread to the threads it unparks, e.g. to tell a woken thread
+/// that it was handed the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UnparkToken(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParkResult {
+    /// Woken by an unpark of the key.
+    Unparked(UnparkToken),
+    /// `validate` returned false, the thread did not park.
+    Invalid,
+    TimedOut,
+}
+
+/// What an [`unpark_one`] or [`unpark_all`] did, also passed to its callback before it
+/// happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UnparkResult {
+    pub unparked_threads: usize,
+    /// Whether threads are left parked on the key.
+    pub have_more_threads: bool,
+}
+
+/// The bucket a primitive of the crate queues its waiters in under `key`.
+pub(crate) fn bucket(key: usize) -> &'static WaitQueue {
+    &BUCKETS[hash(key)]
+}
+
+fn user_bucket(key: usize) -> &'static WaitQueue {
+    &USER_BUCKETS[hash(key)]
+}
+
+fn hash(key: usize) -> usize {
+    ((key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (u64::BITS - BUCKET_BITS)) as usize
+}
+
+/// Parks the calling thread on `key` if `validate` returns true, until it is unparked or
+/// `deadline` passes. `validate` runs with the queue of `key` locked, so no unpark of the
+/// key can come between it and the thread being queued. `before_sleep` runs once queued,
+/// before the thread first sleeps, a panic in it unparks the thread. Neither may park or
+/// unpark.
+pub fn park(key: usize, validate: impl FnOnce() -> bool, before_sleep: impl FnOnce(), deadline: Option<Instant>) -> ParkResult {
+    let queue = user_bucket(key);
+    let mut node = Node::new(0);
+    let node: *mut Node = &mut node;
+    {
+        let mut queue = queue.lock_for(key);
+        if !validate() {
+            return ParkResult::Invalid;
+        }
+        queue.push_back(node);
+    }
+    // the node lives on this stack frame, unwinding must not leave it queued
+    let parked = Parked { queue, key, node };
+    before_sleep();
+    let res = loop {
+        if unsafe { (*node).state() } == State::ACQUIRED {
+            // the unparking thread left its token in the node
+            break ParkResult::Unparked(UnparkToken(unsafe { (*node).acquires } as usize));
+        }
+        if deadline.is_some_and(|end| end <= Instant::now()) {
+            let mut queue = queue.lock_for(key);
+            if unsafe { (*node).state() } == State::PARK {
+                queue.remove(node);
+                break ParkResult::TimedOut;
+            }
+            continue;
+        }
+        match deadline {
+            None => thread::park(),
+            Some(end) => thread::park_timeout(end.saturating_duration_since(Instant::now())),
+        }
+    };
+    mem::forget(parked);
+    res
+}
+
+/// Unlinks a parked node when unwinding.
+struct Parked {
+    queue: &'static WaitQueue,
+    key: usize,
+    node: *mut Node,
+}
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock_for(self.key);
+        // unless an unpark dequeued it already
+        if unsafe { (*self.node).state() } == State::PARK {
+            queue.remove(self.node);
+        }
+    }
+}
+
+/// Unparks the longest parked thread on `key`. `callback` runs with the queue locked and
+/// gets what is about to happen, it returns the token for the woken thread.
+pub fn unpark_one(key: usize, callback: impl FnOnce(UnparkResult) -> UnparkToken) -> UnparkResult {
+    let mut queue = user_bucket(key).lock_for(key);
+    let node = queue.first();
+    let result = UnparkResult {
+        unparked_threads: usize::from(!node.is_null()),
+        have_more_threads: !node.is_null() && !queue.next(node).is_null(),
+    };
+    let token = callback(result);
+    if !node.is_null() {
+        unpark(&mut queue, node, token);
+    }
+    result
+}
+
+/// Unparks every thread parked on `key`. `callback` runs with the queue locked and gets
+/// what is about to happen, it returns the token for all of the woken threads.
+pub fn unpark_all(key: usize, callback: impl FnOnce(UnparkResult) -> UnparkToken) -> UnparkResult {
+    let mut queue = user_bucket(key).lock_for(key);
+    let result = UnparkResult {
+        unparked_threads: queue.len(),
+        have_more_threads: false,
+    };
+    let token = callback(result);
+    loop {
+        let node = queue.first();
+        if node.is_null() {
+            return result;
+        }
+        unpark(&mut queue, node, token);
+    }
+}
+
+fn unpark(queue: &mut WaitQueueGuard<'_>, node: *mut Node, token: UnparkToken) {
+    unsafe { (*node).acquires = token.0 as isize };
+    queue.wake(node, State::ACQUIRED);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{park, unpark_all, unpark_one, ParkResult, UnparkToken};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const HANDOFF: UnparkToken = UnparkToken(1);
+
+    /// One word mutex that unlocks fairly by handing itself to the woken thread. The word
+    /// is 1 when locked and 2 with threads parked on it.
+    struct Mutex(AtomicUsize);
+
+    impl Mutex {
+        fn key(&self) -> usize {
+            self as *const Self as usize
+        }
+
+        fn lock(&self) {
+            if self.0.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return;
+            }
+            // marked parked while still locked, or taken if it was free meanwhile
+            while self.0.swap(2, Ordering::Acquire) != 0 {
+                if park(self.key(), || self.0.load(Ordering::Relaxed) == 2, || {}, None) == ParkResult::Unparked(HANDOFF) {
+                    return;
+                }
+            }
+        }
+
+        fn unlock(&self) {
+            if self.0.compare_exchange(1, 0, Ordering::Release, Ordering::Relaxed).is_ok() {
+                return;
+            }
+            unpark_one(self.key(), |result| {
+                if result.unparked_threads == 0 {
+                    self.0.store(0, Ordering::Release);
+                    return UnparkToken(0);
+                }
+                // the woken thread gets the lock without it ever being free
+                self.0.store(if result.have_more_threads { 2 } else { 1 }, Ordering::Release);
+                HANDOFF
+            });
+        }
+    }
+
+    #[test]
+    fn parking_lot_handoff() {
+        let mutex = Arc::new((Mutex(AtomicUsize::new(0)), AtomicUsize::new(0)));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        mutex.0.lock();
+                        let value = mutex.1.load(Ordering::Relaxed);
+                        thread::yield_now();
+                        mutex.1.store(value + 1, Ordering::Relaxed);
+                        mutex.0.unlock();
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(mutex.1.load(Ordering::Relaxed), 800);
+        assert_eq!(mutex.0 .0.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn parking_lot_park() {
+        // a key of its own, tests run in parallel
+        static KEY: u8 = 0;
+        let key = &KEY as *const u8 as usize;
+        assert_eq!(park(key, || false, || unreachable!(), None), ParkResult::Invalid);
+        let start = Instant::now();
+        assert_eq!(park(key, || true, || {}, Some(start + Duration::from_millis(20))), ParkResult::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(unpark_one(key, |result| {
+            assert_eq!(result.unparked_threads, 0);
+            UnparkToken(0)
+        }).unparked_threads, 0);
+
+        let queued = Arc::new(AtomicBool::new(false));
+        let parked: Vec<_> = (0..3)
+            .map(|i| {
+                let parked = {
+                    let queued = queued.clone();
+                    thread::spawn(move || park(key, || true, || queued.store(true, Ordering::Release), None))
+                };
+                while !queued.swap(false, Ordering::Acquire) {
+                    thread::yield_now();
+                }
+                (i, parked)
+            })
+            .collect();
+        let result = unpark_one(key, |result| {
+            assert!(result.have_more_threads);
+            UnparkToken(7)
+        });
+        assert_eq!(result.unparked_threads, 1);
+        let result = unpark_all(key, |result| {
+            assert_eq!(result.unparked_threads, 2);
+            UnparkToken(9)
+        });
+        assert_eq!(result.unparked_threads, 2);
+        for (i, parked) in parked {
+            let token = if i == 0 { 7 } else { 9 };
+            assert_eq!(parked.join().unwrap(), ParkResult::Unparked(UnparkToken(token)));
+        }
+    }
+
+    #[test]
+    fn parking_lot_before_sleep_panics() {
+        static KEY: u8 = 0;
+        let key = &KEY as *const u8 as usize;
+        let res = std::panic::catch_unwind(|| park(key, || true, || panic!("before sleep"), None));
+        assert!(res.is_err());
+        // the panicking thread is no longer queued
+        let result = unpark_one(key, |result| {
+            assert_eq!(result.unparked_threads, 0);
+            UnparkToken(0)
+        });
+        assert_eq!(result.unparked_threads, 0);
+    }
+}
@@ -0,0 +1,491 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use crate::lock::error::AcquireError;
+use crate::lock::interrupt;
+use crate::lock::utils::{Backoff, Node, State, WaitQueue, WaitQueueGuard};
+use crate::lock::wait::{Parker, SpinThenPark, WaitStrategy};
+
+type OnAdvance = Box<dyn Fn(u32, usize) -> bool + Send + Sync>;
+
+/// Reusable synchronization point with a varying number of parties.
+///
+/// A phaser created with [`Phaser::with_parent`] is a child of a tree. It counts its own
+/// parties and arrives at its parent as a single party once all of them arrived, so no
+/// counter is shared by every party. The phase is owned by the root, children catch up
+/// with it lazily and their waiters wait in the root's queue.
+pub struct Phaser {
+    parent: Option<Arc<Phaser>>,
+    // waiters of the root, for children only the lock guarding their counters
+    queue: WaitQueue,
+    // for children: the phase `unarrived` refers to
+    phase: AtomicU32,
+    parties: AtomicUsize,
+    unarrived: AtomicUsize,
+    terminated: AtomicBool,
+    // for the root: the hook of a completed phase runs
+    advancing: AtomicBool,
+    on_advance: OnAdvance,
+    strategy: Box<dyn WaitStrategy>,
+}
+
+impl Phaser {
+    /// Creates a root phaser. It terminates once an advance leaves no registered party.
+    pub fn new(parties: usize) -> Self {
+        Self::with_on_advance(parties, |_, registered| registered == 0)
+    }
+
+    /// Creates a root phaser calling `on_advance(phase, registered)` each time `phase` is
+    /// completed, before the waiters are released. Returning true terminates the phaser,
+    /// so does a panicking hook.
+    ///
+    /// The hook runs with no phaser of the tree locked. It may query them or force their
+    /// termination, but must not register, arrive or await an advance, as those wait for
+    /// the advance the hook is part of.
+    pub fn with_on_advance<F>(parties: usize, on_advance: F) -> Self
+    where
+        F: Fn(u32, usize) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            parent: None,
+            queue: WaitQueue::new(),
+            phase: AtomicU32::new(0),
+            parties: AtomicUsize::new(parties),
+            unarrived: AtomicUsize::new(parties),
+            terminated: AtomicBool::new(false),
+            advancing: AtomicBool::new(false),
+            on_advance: Box::new(on_advance),
+            strategy: Box::new(SpinThenPark),
+        }
+    }
+
+    /// Sets how the parties arriving at this phaser wait for an advance, by default
+    /// [`SpinThenPark`].
+    pub fn with_wait_strategy(self, strategy: impl WaitStrategy + 'static) -> Self {
+        Self {
+            strategy: Box::new(strategy),
+            ..self
+        }
+    }
+
+    /// Creates a child of `parent`, registered there as one party while it has parties
+    /// of its own. Only the root's advance hook is called.
+    pub fn with_parent(parent: &Arc<Phaser>, parties: usize) -> Self {
+        let phaser = Self {
+            parent: Some(parent.clone()),
+            ..Self::new(0)
+        };
+        phaser.phase.store(phaser.root().phase.load(Ordering::Acquire), Ordering::Relaxed);
+        if parties > 0 {
+            phaser.bulk_register(parties);
+        }
+        phaser
+    }
+
+    /// Adds a party, returns the phase it takes part in or `None` once terminated.
+    pub fn register(&self) -> Option<u32> {
+        self.bulk_register(1)
+    }
+
+    pub fn bulk_register(&self, parties: usize) -> Option<u32> {
+        assert!(parties > 0);
+        // registration of this child at its parent, made with the child unlocked
+        let mut registered = false;
+        loop {
+            let queue = self.queue.lock();
+            if self.root().is_terminated() {
+                return None;
+            }
+            if let Some(phase) = self.pending_advance() {
+                drop(queue);
+                self.root().await_advance(phase);
+                continue;
+            }
+            if let Some(parent) = &self.parent {
+                let empty = self.parties.load(Ordering::Relaxed) == 0;
+                if empty != registered {
+                    // unlocked like an arrival, for the root's hook
+                    drop(queue);
+                    if registered {
+                        // another party registered the child meanwhile
+                        parent.arrive_and_deregister();
+                        registered = false;
+                    } else {
+                        parent.register()?;
+                        registered = true;
+                    }
+                    continue;
+                }
+                if empty {
+                    self.phase.store(self.root().phase.load(Ordering::Acquire), Ordering::Relaxed);
+                }
+            }
+            self.parties.fetch_add(parties, Ordering::Relaxed);
+            self.unarrived.fetch_add(parties, Ordering::Relaxed);
+            return Some(self.phase.load(Ordering::Relaxed));
+        }
+    }
+
+    /// Arrives without waiting for the others. Returns the arrival phase.
+    pub fn arrive(&self) -> Option<u32> {
+        self.do_arrive(false)
+    }
+
+    /// Arrives and leaves the phaser, the remaining parties no longer wait for it.
+    pub fn arrive_and_deregister(&self) -> Option<u32> {
+        self.do_arrive(true)
+    }
+
+    /// Arrives and waits for the others, returns the phase that was advanced to.
+    pub fn arrive_and_await_advance(&self) -> Option<u32> {
+        let phase = self.arrive()?;
+        self.await_advance(phase)
+    }
+
+    /// Waits for `phase` to complete. Returns right away with the current phase if it
+    /// already differs from `phase`, `None` once the phaser is terminated.
+    pub fn await_advance(&self, phase: u32) -> Option<u32> {
+        self.await_phase(phase, false).unwrap_or(None)
+    }
+
+    /// Same as [`Phaser::await_advance`], but gives up with `Err(Interrupted)` once the
+    /// thread is interrupted.
+    pub fn await_advance_interruptibly(&self, phase: u32) -> Result<Option<u32>, AcquireError> {
+        self.await_phase(phase, true)
+    }
+
+    fn await_phase(&self, phase: u32, interruptible: bool) -> Result<Option<u32>, AcquireError> {
+        if interruptible && interrupt::interrupted() {
+            return Err(AcquireError::Interrupted);
+        }
+        let root = self.root();
+        let node = {
+            let mut queue = root.queue.lock();
+            if root.is_terminated() {
+                return Ok(None);
+            }
+            let current = root.phase.load(Ordering::Relaxed);
+            if current != phase {
+                return Ok(Some(current));
+            }
+            let node = Box::into_raw(Box::new(Node::new(0)));
+            queue.push_back(node);
+            node
+        };
+        let backoff = Backoff::new();
+        while unsafe { (*node).state() } == State::PARK {
+            if interruptible && interrupt::is_interrupted() {
+                let mut queue = root.queue.lock();
+                if unsafe { (*node).state() } == State::PARK {
+                    queue.remove(node);
+                    unsafe { (*node).set_state(State::CANCELLED) };
+                    drop(queue);
+                    drop(unsafe { Box::from_raw(node) });
+                    interrupt::interrupted();
+                    return Err(AcquireError::Interrupted);
+                }
+                continue;
+            }
+            self.strategy.wait(&backoff, &Parker::new(None));
+        }
+        drop(unsafe { Box::from_raw(node) });
+        if root.is_terminated() {
+            Ok(None)
+        } else {
+            Ok(Some(root.phase.load(Ordering::Acquire)))
+        }
+    }
+
+    /// Terminates the phaser tree, releasing every waiter.
+    pub fn force_termination(&self) {
+        let root = self.root();
+        let mut queue = root.queue.lock();
+        root.terminate(&mut queue);
+    }
+
+    pub fn phase(&self) -> u32 {
+        self.root().phase.load(Ordering::Acquire)
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.root().terminated.load(Ordering::Acquire)
+    }
+
+    pub fn registered_parties(&self) -> usize {
+        let _queue = self.queue.lock();
+        self.parties.load(Ordering::Relaxed)
+    }
+
+    pub fn unarrived_parties(&self) -> usize {
+        let _queue = self.queue.lock();
+        self.reconcile();
+        self.unarrived.load(Ordering::Relaxed)
+    }
+
+    pub fn parent(&self) -> Option<&Arc<Phaser>> {
+        self.parent.as_ref()
+    }
+
+    fn root(&self) -> &Phaser {
+        let mut phaser = self;
+        while let Some(parent) = &phaser.parent {
+            phaser = parent;
+        }
+        phaser
+    }
+
+    fn do_arrive(&self, deregister: bool) -> Option<u32> {
+        loop {
+            let queue = self.queue.lock();
+            if self.root().is_terminated() {
+                return None;
+            }
+            if let Some(phase) = self.pending_advance() {
+                drop(queue);
+                self.root().await_advance(phase);
+                continue;
+            }
+            let phase = self.phase.load(Ordering::Relaxed);
+            let unarrived = self.unarrived.load(Ordering::Relaxed);
+            assert!(unarrived > 0, "arrival of an unregistered party");
+            self.unarrived.store(unarrived - 1, Ordering::Relaxed);
+            if deregister {
+                self.parties.fetch_sub(1, Ordering::Relaxed);
+            }
+            if unarrived == 1 {
+                match &self.parent {
+                    Some(parent) => {
+                        let deregister = self.parties.load(Ordering::Relaxed) == 0;
+                        // unlocked for the root's hook, parties arriving meanwhile wait
+                        // for the root to advance
+                        drop(queue);
+                        if deregister {
+                            parent.arrive_and_deregister();
+                        } else {
+                            parent.arrive();
+                        }
+                    }
+                    None => self.advance(queue),
+                }
+            }
+            return Some(phase);
+        }
+    }
+
+    /// Completes the phase of the root, given its locked queue. The hook runs unlocked,
+    /// arrivals and registrations wait for the advance meanwhile.
+    fn advance(&self, queue: WaitQueueGuard<'_>) {
+        let phase = self.phase.load(Ordering::Relaxed);
+        let parties = self.parties.load(Ordering::Relaxed);
+        self.advancing.store(true, Ordering::Relaxed);
+        drop(queue);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| (self.on_advance)(phase, parties)));
+        let mut queue = self.queue.lock();
+        self.advancing.store(false, Ordering::Relaxed);
+        match res {
+            Ok(false) if !self.is_terminated() => {
+                self.unarrived.store(parties, Ordering::Relaxed);
+                self.phase.store(phase.wrapping_add(1), Ordering::Release);
+                Self::wake_all(&mut queue);
+            }
+            Ok(_) => self.terminate(&mut queue),
+            Err(err) => {
+                self.terminate(&mut queue);
+                drop(queue);
+                panic::resume_unwind(err);
+            }
+        }
+    }
+
+    fn terminate(&self, queue: &mut WaitQueueGuard<'_>) {
+        self.terminated.store(true, Ordering::Release);
+        Self::wake_all(queue);
+    }
+
+    fn wake_all(queue: &mut WaitQueueGuard<'_>) {
+        loop {
+            let first = queue.first();
+            if first.is_null() {
+                break;
+            }
+            queue.wake(first, State::ACQUIRED);
+        }
+    }
+
+    /// Moves a child to the root's phase once the root advanced past it.
+    fn reconcile(&self) {
+        if self.parent.is_none() {
+            return;
+        }
+        let root_phase = self.root().phase.load(Ordering::Acquire);
+        if self.phase.load(Ordering::Relaxed) != root_phase {
+            self.phase.store(root_phase, Ordering::Relaxed);
+            self.unarrived.store(self.parties.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// The phase this phaser has completed and still waits on the root to advance, if any.
+    fn pending_advance(&self) -> Option<u32> {
+        self.reconcile();
+        let waiting = match self.parent {
+            Some(_) => self.unarrived.load(Ordering::Relaxed) == 0 && self.parties.load(Ordering::Relaxed) > 0,
+            None => self.advancing.load(Ordering::Relaxed),
+        };
+        waiting.then(|| self.phase.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Phaser;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn phaser_dynamic_parties() {
+        let phaser = Arc::new(Phaser::new(1));
+        let handlers: Vec<_> = (0..3)
+            .map(|i| {
+                let phaser = phaser.clone();
+                phaser.register();
+                thread::spawn(move || {
+                    for _ in 0..=i {
+                        phaser.arrive_and_await_advance().unwrap();
+                    }
+                    phaser.arrive_and_deregister();
+                })
+            })
+            .collect();
+        assert_eq!(phaser.registered_parties(), 4);
+        for phase in 0..3 {
+            assert_eq!(phaser.arrive_and_await_advance(), Some(phase + 1));
+        }
+        handlers.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(phaser.registered_parties(), 1);
+        assert_eq!(phaser.arrive_and_deregister(), Some(3));
+        // the default hook terminates once nobody is registered
+        assert!(phaser.is_terminated());
+        assert_eq!(phaser.register(), None);
+    }
+
+    #[test]
+    fn phaser_on_advance() {
+        let advances = Arc::new(AtomicUsize::new(0));
+        let phaser = {
+            let advances = advances.clone();
+            Arc::new(Phaser::with_on_advance(2, move |phase, registered| {
+                assert_eq!(registered, 2);
+                advances.fetch_add(1, Ordering::SeqCst);
+                phase == 2
+            }))
+        };
+        let other = {
+            let phaser = phaser.clone();
+            thread::spawn(move || while phaser.arrive_and_await_advance().is_some() {})
+        };
+        assert_eq!(phaser.arrive_and_await_advance(), Some(1));
+        assert_eq!(phaser.arrive_and_await_advance(), Some(2));
+        assert_eq!(phaser.arrive_and_await_advance(), None);
+        other.join().unwrap();
+        assert_eq!(advances.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn phaser_on_advance_queries() {
+        static ROOT: OnceLock<Arc<Phaser>> = OnceLock::new();
+        static CHILD: OnceLock<Arc<Phaser>> = OnceLock::new();
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        let root = ROOT.get_or_init(|| {
+            Arc::new(Phaser::with_on_advance(1, |phase, _| {
+                let (root, child) = (ROOT.get().unwrap(), CHILD.get().unwrap());
+                // every phaser of the tree can be looked at from the hook
+                let seen = root.registered_parties() * 100 + root.unarrived_parties() * 10 + child.registered_parties();
+                SEEN.store(seen, Ordering::SeqCst);
+                if phase == 1 {
+                    root.force_termination();
+                }
+                false
+            }))
+        });
+        let child = CHILD.get_or_init(|| Arc::new(Phaser::with_parent(root, 2)));
+        let other = thread::spawn(|| CHILD.get().unwrap().arrive_and_await_advance());
+        assert_eq!(root.arrive(), Some(0));
+        assert_eq!(child.arrive_and_await_advance(), Some(1));
+        assert_eq!(other.join().unwrap(), Some(1));
+        assert_eq!(SEEN.load(Ordering::SeqCst), 202);
+        // the hook of the next phase terminates the tree
+        let other = thread::spawn(|| CHILD.get().unwrap().arrive_and_await_advance());
+        child.arrive();
+        assert_eq!(root.arrive_and_await_advance(), None);
+        assert_eq!(other.join().unwrap(), None);
+        assert!(root.is_terminated());
+    }
+
+    #[test]
+    fn phaser_register_during_advance() {
+        static ROOT: OnceLock<Arc<Phaser>> = OnceLock::new();
+        static CHILD: OnceLock<Arc<Phaser>> = OnceLock::new();
+        static ADVANCING: AtomicBool = AtomicBool::new(false);
+        let root = ROOT.get_or_init(|| {
+            Arc::new(Phaser::with_on_advance(1, |_, _| {
+                ADVANCING.store(true, Ordering::SeqCst);
+                // lets the child's registration reach the root
+                thread::sleep(Duration::from_millis(20));
+                CHILD.get().unwrap().registered_parties();
+                false
+            }))
+        });
+        let child = CHILD.get_or_init(|| Arc::new(Phaser::with_parent(root, 0)));
+        let advance = thread::spawn(|| ROOT.get().unwrap().arrive());
+        while !ADVANCING.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert_eq!(child.register(), Some(1));
+        assert_eq!(advance.join().unwrap(), Some(0));
+        assert_eq!(root.registered_parties(), 2);
+        assert_eq!(child.registered_parties(), 1);
+    }
+
+    #[test]
+    fn phaser_await_advance() {
+        let phaser = Arc::new(Phaser::new(2));
+        assert_eq!(phaser.await_advance(5), Some(0));
+        let waiter = {
+            let phaser = phaser.clone();
+            thread::spawn(move || phaser.await_advance(0))
+        };
+        assert_eq!(phaser.arrive(), Some(0));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        assert_eq!(phaser.arrive(), Some(0));
+        assert_eq!(waiter.join().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn phaser_tiered() {
+        let root = Arc::new(Phaser::new(0));
+        let children: Vec<_> = (0..4).map(|_| Arc::new(Phaser::with_parent(&root, 4))).collect();
+        assert_eq!(root.registered_parties(), 4);
+        let arrivals = Arc::new(AtomicUsize::new(0));
+        let handlers: Vec<_> = children
+            .iter()
+            .flat_map(|child| (0..4).map(move |_| child.clone()))
+            .map(|child| {
+                let arrivals = arrivals.clone();
+                thread::spawn(move || {
+                    for phase in 0..5 {
+                        arrivals.fetch_add(1, Ordering::SeqCst);
+                        assert_eq!(child.arrive_and_await_advance(), Some(phase + 1));
+                        // every party of every child arrived before anyone moved on
+                        assert!(arrivals.load(Ordering::SeqCst) >= 16 * (phase as usize + 1));
+                    }
+                    child.arrive_and_deregister();
+                })
+            })
+            .collect();
+        handlers.into_iter().for_each(|h| h.join().unwrap());
+        assert!(children.iter().all(|child| child.registered_parties() == 0));
+        assert!(root.is_terminated());
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::lock::error::AcquireError;
+use crate::lock::semaphore::Semaphore;
+use crate::lock::wait::WaitStrategy;
+
+/// Token bucket on top of a [`Semaphore`]. Tokens are refilled lazily from the time
+/// elapsed since the last refill whenever the limiter is used, there is no refill thread.
+pub struct RateLimiter {
+    permits: Semaphore,
+    burst: isize,
+    // nanoseconds needed to refill one permit
+    interval: u64,
+    origin: Instant,
+    // nanoseconds after `origin` the refilled permits are accounted up to
+    last_refill: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Refills `rate` permits every `per`, holding at most `burst` of them. The bucket starts full.
+    pub fn new(rate: isize, per: Duration, burst: isize) -> Self {
+        assert!(rate > 0 && burst > 0);
+        let interval = (per.as_nanos() / rate as u128).clamp(1, u64::MAX.into()) as u64;
+        Self {
+            permits: Semaphore::with_max_permits(burst, false, burst),
+            burst,
+            interval,
+            origin: Instant::now(),
+            last_refill: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets how threads wait for permits, by default [`Adaptive`](crate::lock::Adaptive).
+    pub fn with_wait_strategy(self, strategy: impl WaitStrategy + 'static) -> Self {
+        Self {
+            permits: self.permits.with_wait_strategy(strategy),
+            ..self
+        }
+    }
+
+    /// Blocks until `n` permits have been refilled and taken, or `deadline` passes.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is not positive or more than `burst`, the bucket never holds that many.
+    pub fn acquire(&self, n: isize, deadline: Option<Instant>) -> Result<(), AcquireError> {
+        self.acquire_permits(n, deadline, false)
+    }
+
+    /// Same as [`RateLimiter::acquire`], but gives up once the thread is interrupted.
+    pub fn acquire_interruptibly(&self, n: isize, deadline: Option<Instant>) -> Result<(), AcquireError> {
+        self.acquire_permits(n, deadline, true)
+    }
+
+    fn acquire_permits(&self, n: isize, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+        assert!(n > 0 && n <= self.burst, "requested permits exceed the burst");
+        loop {
+            self.refill();
+            // a refill too far off to tell is waited for like no refill at all
+            let until = match (deadline, Instant::now().checked_add(self.time_until(n))) {
+                (Some(deadline), Some(refilled)) => Some(deadline.min(refilled)),
+                (deadline, refilled) => deadline.or(refilled),
+            };
+            let permit = if interruptible {
+                self.permits.acquire_interruptibly(n, until)
+            } else {
+                self.permits.acquire(n, until)
+            };
+            match permit {
+                Ok(permit) => {
+                    permit.forget();
+                    return Ok(());
+                }
+                Err(AcquireError::Timeout) if deadline.is_none_or(|deadline| Instant::now() < deadline) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn try_acquire(&self, n: isize) -> bool {
+        assert!(n > 0);
+        self.refill();
+        match self.permits.try_acquire(n) {
+            Some(permit) => {
+                permit.forget();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How long until at least one permit is available, zero if one already is.
+    pub fn time_until_next_permit(&self) -> Duration {
+        self.refill();
+        self.time_until(1)
+    }
+
+    pub fn available_permits(&self) -> isize {
+        self.refill();
+        self.permits.available_permits()
+    }
+
+    fn now(&self) -> u64 {
+        self.origin.elapsed().as_nanos() as u64
+    }
+
+    fn refill(&self) {
+        let now = self.now();
+        let mut last = self.last_refill.load(Ordering::Acquire);
+        loop {
+            let tokens = now.saturating_sub(last) / self.interval;
+            if tokens == 0 {
+                return;
+            }
+            // tokens that do not fit in the bucket are lost, so the clock moves on regardless
+            match self.last_refill.compare_exchange_weak(last, last + tokens * self.interval, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    self.permits.add_permits(tokens.min(self.burst as u64) as isize);
+                    return;
+                }
+                Err(actual) => last = actual,
+            }
+        }
+    }
+
+    fn time_until(&self, n: isize) -> Duration {
+        let missing = n - self.permits.available_permits();
+        if missing <= 0 {
+            return Duration::ZERO;
+        }
+        let ready = self.last_refill.load(Ordering::Acquire).saturating_add((missing as u64).saturating_mul(self.interval));
+        Duration::from_nanos(ready.saturating_sub(self.now()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimiter;
+    use crate::lock::AcquireError;
+    use std::{sync::Arc, thread, time::{Duration, Instant}};
+
+    #[test]
+    fn rate_limiter_burst() {
+        let limiter = RateLimiter::new(20, Duration::from_secs(1), 2);
+        assert!(limiter.try_acquire(2));
+        assert!(!limiter.try_acquire(1));
+        let wait = limiter.time_until_next_permit();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50));
+        let deadline = Instant::now() + Duration::from_millis(5);
+        assert_eq!(limiter.acquire(1, Some(deadline)), Err(AcquireError::Timeout));
+
+        let start = Instant::now();
+        limiter.acquire(1, None).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        thread::sleep(Duration::from_millis(200));
+        // refilled up to the burst only
+        assert_eq!(limiter.available_permits(), 2);
+    }
+
+    #[test]
+    fn rate_limiter_threads() {
+        let limiter = Arc::new(RateLimiter::new(100, Duration::from_secs(1), 1));
+        let start = Instant::now();
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    for _ in 0..3 {
+                        limiter.acquire(1, None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        handlers.into_iter().for_each(|h| h.join().unwrap());
+        // one permit up front, the other eleven refilled every 10ms
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn rate_limiter_slow_refill() {
+        // the next refill is further off than an Instant reaches
+        let limiter = RateLimiter::new(1, Duration::MAX, 1);
+        assert!(limiter.try_acquire(1));
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(limiter.acquire(1, Some(deadline)), Err(AcquireError::Timeout));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceed the burst")]
+    fn rate_limiter_over_burst() {
+        let limiter = RateLimiter::new(20, Duration::from_secs(1), 2);
+        let _ = limiter.acquire(3, None);
+    }
+}
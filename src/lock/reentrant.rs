@@ -1,4 +1,14 @@
-use std::{sync::atomic::{AtomicPtr, AtomicUsize, Ordering}, cell::Cell};
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, marker::PhantomData, ops::Deref};
+use std::time::{Duration, Instant};
+use crate::lock::error::AcquireError;
+use crate::lock::interrupt;
+use crate::lock::lockdep::LockdepTracker;
+#[cfg(doc)]
+use crate::lock::ReentrantReadWriteLock;
+#[cfg(feature = "stats")]
+use crate::lock::stats::LockStats;
+use crate::lock::utils::{Backoff, Hooks, Node, QueuedSynchronizer, State, Synchronizer, WaitQueue};
+use crate::lock::wait::{Parker, WaitStrategy};
 
 /// ReentrantLock
 #[derive(Debug,Default)]
@@ -12,79 +22,569 @@ impl IdGenerator{
     }
 }
 
-struct Node{
-    next:*mut Node,
-    thread:std::thread::Thread,
-    thread_id:usize,
+// id 0 stands for "no thread"
+static ID_GENERATOR:IdGenerator=IdGenerator{gen:AtomicUsize::new(1)};
+
+thread_local! {
+    static THREAD_ID:usize=ID_GENERATOR.gen_id();
+}
+
+/// Id of the calling thread, unique for the lifetime of the process and never 0.
+pub(crate) fn current_thread_id()->usize{
+    THREAD_ID.with(|id|*id)
+}
+
+/// Mutual exclusion lock that the owning thread can acquire again. Each `lock` of the
+/// owner increments a hold count, the lock is released once every guard is dropped.
+///
+/// Since the owner may hold several guards at once they only hand out `&T`, use a `Cell`
+/// or `RefCell` inside for mutation.
+///
+/// A non-fair lock can be taken by an arriving thread whenever it is free, even with
+/// threads queued, which keeps it busy but may leave a waiter behind for long. A fair lock
+/// is handed over to the longest waiting thread on unlock and only taken directly while
+/// nobody waits.
+pub struct ReentrantLock<T: ?Sized> {
+    // the state counts the holds of the owner
+    sync:QueuedSynchronizer,
+    owner:AtomicUsize,
+    fair:bool,
+    lockdep:LockdepTracker,
+    data:T
+}
+
+/// Holds the lock until dropped. It can't leave the owning thread.
+#[must_use]
+pub struct ReentrantLockGuard<'a, T: ?Sized> {
+    lock:&'a ReentrantLock<T>,
+    _marker:PhantomData<*const ()>
 }
 
-impl Node{
-    fn new(id:usize)->Self{
+unsafe impl<T: ?Sized + Send> Send for ReentrantLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for ReentrantLock<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for ReentrantLockGuard<'_, T> {}
+
+impl<T> ReentrantLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn new(data:T,fair:bool)->Self{
         Self{
-            next:std::ptr::null_mut(),
-            thread:std::thread::current(),
-            thread_id:id,
+            sync:QueuedSynchronizer::new(0,"ReentrantLock"),
+            owner:AtomicUsize::new(0),
+            fair,
+            lockdep:LockdepTracker::new("ReentrantLock"),
+            data
         }
     }
-}
 
-pub(crate) struct ReentrantLock {
-    head:AtomicPtr<Node>,
-    tail:AtomicPtr<Node>,
-    hold_thread:Cell<usize>,
-    id_generator:IdGenerator,
-    fair:bool
+    /// Sets how threads wait for the lock and its conditions, by default
+    /// [`Adaptive`](crate::lock::Adaptive).
+    pub fn with_wait_strategy(mut self,strategy:impl WaitStrategy+'static)->Self{
+        self.sync.set_wait_strategy(strategy);
+        self
+    }
+
+    pub fn into_inner(self)->T{
+        self.data
+    }
 }
 
-impl ReentrantLock {
-    pub(crate) fn new(f:Option<bool>)->Self{
-        let fair=f.is_some();
-        let id_generator=IdGenerator::default();
-        let id=id_generator.gen_id();
-        let node=Box::into_raw(Box::new(Node::new(id)));
-        Self{
-            head:AtomicPtr::new(node),
-            tail:AtomicPtr::new(node),
-            hold_thread:Cell::new(id),
-            id_generator,
-            fair
+impl<T: ?Sized> ReentrantLock<T> {
+    /// Blocks until the lock is free or already held by the calling thread.
+    pub fn lock(&self)->ReentrantLockGuard<'_, T>{
+        self.lockdep.acquiring();
+        let _=Hooks(self).acquire(1,None,false);
+        ReentrantLockGuard{lock:self,_marker:PhantomData}
+    }
+
+    /// Blocks until the lock is taken, `deadline` passes or the thread is interrupted.
+    pub fn lock_interruptibly(&self,deadline:Option<Instant>)->Result<ReentrantLockGuard<'_, T>,AcquireError>{
+        self.lockdep.acquiring();
+        Hooks(self).acquire(1,deadline,true)?;
+        Ok(ReentrantLockGuard{lock:self,_marker:PhantomData})
+    }
+
+    /// Takes the lock only if that needs no waiting. A fair lock is not taken from threads
+    /// already waiting for it.
+    pub fn try_lock(&self)->Option<ReentrantLockGuard<'_, T>>{
+        if Hooks(self).acquire_now(1){
+            Some(ReentrantLockGuard{lock:self,_marker:PhantomData})
+        }else{
+            None
         }
     }
 
-    pub(crate) fn lock(&self){
+    /// Waits at most `timeout` for the lock, a timeout too large to represent waits without
+    /// a deadline.
+    pub fn try_lock_for(&self,timeout:Duration)->Option<ReentrantLockGuard<'_, T>>{
+        self.lockdep.acquiring();
+        if Hooks(self).acquire(1,Instant::now().checked_add(timeout),false).is_ok(){
+            Some(ReentrantLockGuard{lock:self,_marker:PhantomData})
+        }else{
+            None
+        }
+    }
+
+    /// Called by a guard of the owning thread.
+    fn unlock(&self){
+        debug_assert_eq!(self.owner.load(Ordering::Relaxed),current_thread_id());
+        Hooks(self).release(1);
+    }
+
+    /// Gives the lock up whatever the hold count is, returns the count to restore.
+    fn unlock_fully(&self)->usize{
+        let holds=self.sync.state().load(Ordering::Relaxed);
+        Hooks(self).release(holds as isize);
+        holds as usize
+    }
+
+    fn relock(&self,holds:usize){
+        let _=Hooks(self).acquire(1,None,false);
+        self.sync.state().store(holds as i32,Ordering::Relaxed);
+    }
+
+    /// Creates a condition to await with guards of this lock. A lock can have any number of
+    /// them, they wait with the strategy the lock has when they are created.
+    pub fn new_condition(&self)->Condition{
+        Condition::new(self.sync.id(),self.sync.wait_strategy().clone())
+    }
 
+    pub fn is_fair(&self)->bool{
+        self.fair
     }
 
-    pub(crate) fn try_lock(&self){
-        if self.fair{
+    /// Whether any thread waits for the lock.
+    pub fn has_queued_threads(&self)->bool{
+        self.sync.has_queued_threads()
+    }
 
+    pub fn is_locked(&self)->bool{
+        self.owner.load(Ordering::Relaxed)!=0
+    }
+
+    pub fn is_held_by_current_thread(&self)->bool{
+        self.owner.load(Ordering::Relaxed)==current_thread_id()
+    }
+
+    /// Number of guards the calling thread holds, 0 if it is not the owner.
+    pub fn hold_count(&self)->usize{
+        if self.is_held_by_current_thread(){
+            self.sync.state().load(Ordering::Relaxed) as usize
         }else{
-            
+            0
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self)->LockStats{
+        self.sync.stats()
+    }
+}
+
+impl<T: ?Sized> Synchronizer for Hooks<'_, ReentrantLock<T>> {
+    fn sync(&self)->&QueuedSynchronizer{
+        &self.sync
+    }
+
+    fn try_acquire(&self,_arg:isize,queued:bool)->bool{
+        let id=current_thread_id();
+        let holds=self.sync.state();
+        if self.owner.load(Ordering::Relaxed)==id{
+            assert!(holds.load(Ordering::Relaxed)<i32::MAX,"maximum lock count exceeded");
+            holds.fetch_add(1,Ordering::Relaxed);
+            return true;
+        }
+        if self.fair&&!queued&&self.sync.has_queued_threads(){
+            return false;
         }
+        if holds.compare_exchange(0,1,Ordering::Acquire,Ordering::Relaxed).is_err(){
+            return false;
+        }
+        self.owner.store(id,Ordering::Relaxed);
+        self.sync.deadlock().acquired(1);
+        self.lockdep.acquired(1);
+        true
+    }
+
+    fn try_release(&self,arg:isize)->bool{
+        let holds=self.sync.state().load(Ordering::Relaxed)-arg as i32;
+        if holds>0{
+            self.sync.state().store(holds,Ordering::Relaxed);
+            return false;
+        }
+        self.sync.deadlock().released(1);
+        self.lockdep.released(1);
+        self.owner.store(0,Ordering::Relaxed);
+        self.sync.state().store(0,Ordering::Release);
+        true
+    }
+}
+
+impl<T: ?Sized> Deref for ReentrantLockGuard<'_, T> {
+    type Target=T;
+
+    fn deref(&self)->&T{
+        &self.lock.data
+    }
+}
+
+impl<T: ?Sized> Drop for ReentrantLockGuard<'_, T> {
+    fn drop(&mut self){
+        self.lock.unlock();
     }
+}
 
-    pub(crate) fn unlock(&self){
-        // unlock
+pub(crate) mod sealed {
+    pub trait ConditionGuard {
+        /// Identity of the guarded lock, see `QueuedSynchronizer::id`.
+        fn lock_id(&self)->usize;
+        /// Releases every hold of the calling thread, returns what to restore.
+        fn unlock_fully(&self)->usize;
+        fn relock(&self,holds:usize);
     }
 }
 
+/// Guard of a lock that [`Condition`]s can be awaited with.
+pub trait ConditionGuard: sealed::ConditionGuard {}
+
+impl<T: ?Sized> sealed::ConditionGuard for ReentrantLockGuard<'_, T> {
+    fn lock_id(&self)->usize{
+        self.lock.sync.id()
+    }
+
+    fn unlock_fully(&self)->usize{
+        self.lock.unlock_fully()
+    }
 
-pub(crate) struct Condition{
-    first:*mut Node,
-    last:*mut Node
+    fn relock(&self,holds:usize){
+        self.lock.relock(holds)
+    }
+}
+
+impl<T: ?Sized> ConditionGuard for ReentrantLockGuard<'_, T> {}
+
+/// Queue of threads waiting for a state guarded by a [`ReentrantLock`] or the write lock of
+/// a [`ReentrantReadWriteLock`] to change, see [`ReentrantLock::new_condition`].
+///
+/// Awaiting releases the lock fully, whatever the hold count of the thread is, and takes
+/// it back with the same hold count before returning, also when it fails. Wake-ups are not
+/// spurious but the awaited state may change again before the lock is retaken, so await
+/// in a loop. All awaits but `await_uninterruptibly` return `Err(Interrupted)` once the
+/// thread is interrupted.
+pub struct Condition{
+    queue:WaitQueue,
+    // `QueuedSynchronizer::id` of the lock
+    lock:usize,
+    strategy:Arc<dyn WaitStrategy>
 }
 
 impl Condition{
-    fn new(){
+    pub(crate) fn new(lock:usize,strategy:Arc<dyn WaitStrategy>)->Self{
+        Condition{queue:WaitQueue::new(),lock,strategy}
+    }
+
+    /// Waits until signalled. `guard` proves the lock is held.
+    ///
+    /// # Panics
+    ///
+    /// If `guard` does not belong to the lock that created the condition. Every await
+    /// checks this.
+    pub fn r#await<G: ConditionGuard>(&self,guard:&G)->Result<(),AcquireError>{
+        self.await_inner(guard,None,true)
+    }
+
+    /// A timeout too large to represent waits without a deadline.
+    pub fn await_timeout<G: ConditionGuard>(&self,guard:&G,timeout:Duration)->Result<(),AcquireError>{
+        self.await_inner(guard,Instant::now().checked_add(timeout),true)
+    }
+
+    pub fn await_until<G: ConditionGuard>(&self,guard:&G,deadline:Instant)->Result<(),AcquireError>{
+        self.await_inner(guard,Some(deadline),true)
+    }
+
+    /// Waits until signalled, interrupts are left pending.
+    pub fn await_uninterruptibly<G: ConditionGuard>(&self,guard:&G){
+        let _=self.await_inner(guard,None,false);
+    }
+
+    fn await_inner<G: ConditionGuard>(&self,guard:&G,deadline:Option<Instant>,interruptible:bool)->Result<(),AcquireError>{
+        assert!(guard.lock_id()==self.lock,"condition awaited with a guard of another lock");
+        if interruptible&&interrupt::interrupted(){
+            return Err(AcquireError::Interrupted);
+        }
+        let node=Box::into_raw(Box::new(Node::new(0)));
+        // queued before the lock is released, a signal in between is not lost
+        self.queue.lock().push_back(node);
+        let holds=guard.unlock_fully();
+        let backoff=Backoff::new();
+        let res=loop {
+            if unsafe{(*node).state()}!=State::PARK{
+                break Ok(());
+            }
+            let interrupted=interruptible&&interrupt::is_interrupted();
+            if interrupted||deadline.is_some_and(|end|end<=Instant::now()){
+                let mut queue=self.queue.lock();
+                if unsafe{(*node).state()}==State::PARK{
+                    queue.remove(node);
+                    unsafe{(*node).set_state(State::CANCELLED)};
+                    if interrupted{
+                        interrupt::interrupted();
+                        break Err(AcquireError::Interrupted);
+                    }
+                    break Err(AcquireError::Timeout);
+                }
+                continue;
+            }
+            self.strategy.wait(&backoff,&Parker::new(deadline));
+        };
+        drop(unsafe{Box::from_raw(node)});
+        guard.relock(holds);
+        res
+    }
+
+    /// Wakes the longest waiting thread, if any.
+    pub fn signal(&self){
+        let mut queue=self.queue.lock();
+        let first=queue.first();
+        if !first.is_null(){
+            queue.wake(first,State::ACQUIRED);
+        }
+    }
 
+    pub fn signal_all(&self){
+        let mut queue=self.queue.lock();
+        loop {
+            let first=queue.first();
+            if first.is_null(){
+                break;
+            }
+            queue.wake(first,State::ACQUIRED);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Condition, ReentrantLock};
+    use crate::lock::{AcquireError, Interrupter, SpinThenPark};
+    use std::sync::mpsc;
+    use std::{cell::{Cell, RefCell}, collections::VecDeque, sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn reentrant_hold_count(){
+        let lock=Arc::new(ReentrantLock::new(Cell::new(0),false));
+        let outer=lock.lock();
+        let inner=lock.lock();
+        assert_eq!(lock.hold_count(),2);
+        inner.set(inner.get()+1);
+        drop(inner);
+        assert!(lock.is_held_by_current_thread());
+        let other={
+            let lock=lock.clone();
+            thread::spawn(move ||{
+                assert!(!lock.is_held_by_current_thread());
+                assert_eq!(lock.hold_count(),0);
+                assert!(lock.try_lock().is_none());
+                lock.lock().get()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        outer.set(outer.get()+1);
+        drop(outer);
+        assert_eq!(other.join().unwrap(),2);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn reentrant_mutual_exclusion(){
+        for fair in [false,true]{
+            let lock=Arc::new(ReentrantLock::new(Cell::new(0usize),fair));
+            let threads:Vec<_>=(0..8).map(|_|{
+                let lock=lock.clone();
+                thread::spawn(move ||{
+                    for _ in 0..1000{
+                        let guard=lock.lock();
+                        let value=guard.get();
+                        // re-entering must not deadlock nor let anyone else in
+                        let again=lock.lock();
+                        thread::yield_now();
+                        again.set(value+1);
+                    }
+                })
+            }).collect();
+            threads.into_iter().for_each(|t|t.join().unwrap());
+            assert_eq!(lock.lock().get(),8000);
+        }
+    }
+
+    struct BoundedBuffer{
+        items:ReentrantLock<RefCell<VecDeque<usize>>>,
+        not_full:Condition,
+        not_empty:Condition,
+        capacity:usize
+    }
+
+    impl BoundedBuffer{
+        fn new(capacity:usize)->Self{
+            let items=ReentrantLock::new(RefCell::new(VecDeque::new()),true);
+            Self{not_full:items.new_condition(),not_empty:items.new_condition(),items,capacity}
+        }
+
+        fn put(&self,item:usize){
+            let items=self.items.lock();
+            while items.borrow().len()==self.capacity{
+                self.not_full.r#await(&items).unwrap();
+            }
+            items.borrow_mut().push_back(item);
+            self.not_empty.signal();
+        }
+
+        fn take(&self)->usize{
+            let items=self.items.lock();
+            while items.borrow().is_empty(){
+                self.not_empty.r#await(&items).unwrap();
+            }
+            let item=items.borrow_mut().pop_front().unwrap();
+            self.not_full.signal();
+            item
+        }
     }
-    fn wait(){
 
+    #[test]
+    fn condition_bounded_buffer(){
+        let buffer=Arc::new(BoundedBuffer::new(2));
+        let producers:Vec<_>=(0..4).map(|p|{
+            let buffer=buffer.clone();
+            thread::spawn(move ||(0..100).for_each(|i|buffer.put(p*100+i)))
+        }).collect();
+        let mut taken:Vec<_>=(0..400).map(|_|buffer.take()).collect();
+        producers.into_iter().for_each(|t|t.join().unwrap());
+        taken.sort_unstable();
+        assert_eq!(taken,(0..400).collect::<Vec<_>>());
     }
-    fn signal(){
 
+    #[test]
+    fn condition_restores_hold_count(){
+        let lock=Arc::new(ReentrantLock::new((),false));
+        let cond=Arc::new(lock.new_condition());
+        let outer=lock.lock();
+        let inner=lock.lock();
+        assert_eq!(cond.await_timeout(&inner,Duration::from_millis(10)),Err(AcquireError::Timeout));
+        assert_eq!(lock.hold_count(),2);
+        let signaller={
+            let (lock,cond)=(lock.clone(),cond.clone());
+            thread::spawn(move ||{
+                // only possible once the waiter released every hold
+                let _guard=lock.lock();
+                cond.signal();
+            })
+        };
+        cond.r#await(&outer).unwrap();
+        assert_eq!(lock.hold_count(),2);
+        drop(inner);
+        drop(outer);
+        signaller.join().unwrap();
+        assert!(!lock.is_locked());
     }
-    fn signal_all(){
 
+    #[test]
+    #[should_panic(expected="guard of another lock")]
+    fn condition_foreign_guard(){
+        let (lock,other)=(ReentrantLock::new((),false),ReentrantLock::new((),false));
+        let cond=lock.new_condition();
+        let _guard=lock.lock();
+        let _=cond.await_timeout(&other.lock(),Duration::from_millis(10));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn condition_outlives_strategy_change(){
+        let lock=ReentrantLock::new((),false);
+        let cond=lock.new_condition();
+        // the condition keeps waiting the way the lock did when it was created
+        let (lock,cond)=(Arc::new(lock.with_wait_strategy(SpinThenPark)),Arc::new(cond));
+        let guard=lock.lock();
+        let signaller={
+            let (lock,cond)=(lock.clone(),cond.clone());
+            thread::spawn(move ||{
+                let _guard=lock.lock();
+                cond.signal();
+            })
+        };
+        cond.await_timeout(&guard,Duration::MAX).unwrap();
+        drop(guard);
+        signaller.join().unwrap();
+    }
+
+    #[test]
+    fn reentrant_fair_order(){
+        let lock=Arc::new(ReentrantLock::new(RefCell::new(Vec::new()),true));
+        let guard=lock.lock();
+        let waiters:Vec<_>=(0..4).map(|i|{
+            let lock=lock.clone();
+            let waiter=thread::spawn(move ||lock.lock().borrow_mut().push(i));
+            // queued one after the other
+            thread::sleep(Duration::from_millis(20));
+            waiter
+        }).collect();
+        assert!(lock.has_queued_threads());
+        // no barging past the queue, not even for a timed attempt
+        let other={
+            let lock=lock.clone();
+            thread::spawn(move ||lock.try_lock().is_none()&&lock.try_lock_for(Duration::from_millis(10)).is_none())
+        };
+        assert!(other.join().unwrap());
+        drop(guard);
+        waiters.into_iter().for_each(|t|t.join().unwrap());
+        assert_eq!(*lock.lock().borrow(),vec![0,1,2,3]);
+    }
+
+    #[test]
+    fn reentrant_try_lock_for(){
+        let lock=Arc::new(ReentrantLock::new((),false));
+        let guard=lock.lock();
+        let waiter={
+            let lock=lock.clone();
+            thread::spawn(move ||{
+                assert!(lock.try_lock_for(Duration::from_millis(10)).is_none());
+                // the timed out waiter left the queue
+                assert!(!lock.has_queued_threads());
+                lock.try_lock_for(Duration::MAX).is_some()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn reentrant_interruptible(){
+        let lock=Arc::new(ReentrantLock::new((),true));
+        let cond=Arc::new(lock.new_condition());
+        let (tx,rx)=mpsc::channel();
+        let guard=lock.lock();
+        let waiter={
+            let (lock,cond)=(lock.clone(),cond.clone());
+            thread::spawn(move ||{
+                tx.send(Interrupter::current()).unwrap();
+                assert_eq!(lock.lock_interruptibly(None).err(),Some(AcquireError::Interrupted));
+                let guard=lock.lock_interruptibly(None).unwrap();
+                let _again=lock.lock();
+                tx.send(Interrupter::current()).unwrap();
+                assert_eq!(cond.r#await(&guard),Err(AcquireError::Interrupted));
+                // the lock was taken back before returning
+                lock.hold_count()
+            })
+        };
+        let interrupter=rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        interrupter.interrupt();
+        thread::sleep(Duration::from_millis(20));
+        // queued again after the interrupted attempt
+        assert!(lock.has_queued_threads());
+        drop(guard);
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        interrupter.interrupt();
+        assert_eq!(waiter.join().unwrap(),2);
+    }
+}
@@ -0,0 +1,507 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use crate::lock::error::AcquireError;
+use crate::lock::lockdep::LockdepTracker;
+use crate::lock::reentrant::{current_thread_id, sealed, Condition, ConditionGuard};
+use crate::lock::utils::{Hooks, QueuedSynchronizer, Synchronizer};
+use crate::lock::wait::WaitStrategy;
+
+// set in the state while the write lock is held, the other bits count the read holds
+const WRITE: i32 = i32::MIN;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // read holds of the calling thread, per lock id
+    static READ_HOLDS: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Read-write lock whose read and write locks can both be acquired again by the threads
+/// holding them.
+///
+/// The writer may also take the read lock and then release the write lock, downgrading
+/// to a reader, while a reader can't upgrade. Fairness works like for [`ReentrantLock`](crate::lock::ReentrantLock),
+/// a non-fair lock only keeps new readers out while a writer waits so writers are not
+/// starved. Both guards only hand out `&T`, since the writer may hold read guards too.
+pub struct ReentrantReadWriteLock<T: ?Sized> {
+    // tells this lock's read holds apart in the thread local counts
+    id: usize,
+    sync: QueuedSynchronizer,
+    writer: AtomicUsize,
+    // only touched by the writer
+    write_holds: AtomicUsize,
+    // writers in the slow path, non-fair readers don't barge past them
+    waiting_writers: AtomicUsize,
+    fair: bool,
+    lockdep: LockdepTracker,
+    data: T,
+}
+
+/// A read hold, released when dropped. It can't leave the owning thread.
+#[must_use]
+pub struct ReadLockGuard<'a, T: ?Sized> {
+    lock: &'a ReentrantReadWriteLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+/// A write hold, released when dropped. It can't leave the owning thread.
+#[must_use]
+pub struct WriteLockGuard<'a, T: ?Sized> {
+    lock: &'a ReentrantReadWriteLock<T>,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for ReentrantReadWriteLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for ReentrantReadWriteLock<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for ReadLockGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for WriteLockGuard<'_, T> {}
+
+impl<T> ReentrantReadWriteLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn new(data: T, fair: bool) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            sync: QueuedSynchronizer::new(0, "ReentrantReadWriteLock"),
+            writer: AtomicUsize::new(0),
+            write_holds: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            fair,
+            lockdep: LockdepTracker::new("ReentrantReadWriteLock"),
+            data,
+        }
+    }
+
+    /// Sets how threads wait for the lock and its conditions, by default
+    /// [`Adaptive`](crate::lock::Adaptive).
+    pub fn with_wait_strategy(mut self, strategy: impl WaitStrategy + 'static) -> Self {
+        self.sync.set_wait_strategy(strategy);
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: ?Sized> ReentrantReadWriteLock<T> {
+    /// Blocks until the read lock is taken. Readers already holding it and the writer
+    /// get it right away.
+    pub fn read(&self) -> ReadLockGuard<'_, T> {
+        self.lockdep.acquiring();
+        let _ = Hooks(self).acquire_shared(1, None, false);
+        ReadLockGuard { lock: self, _marker: PhantomData }
+    }
+
+    pub fn try_read(&self) -> Option<ReadLockGuard<'_, T>> {
+        Hooks(self).acquire_shared_now(1).then(|| ReadLockGuard { lock: self, _marker: PhantomData })
+    }
+
+    /// Blocks until the read lock is taken, `deadline` passes or the thread is interrupted.
+    pub fn read_interruptibly(&self, deadline: Option<Instant>) -> Result<ReadLockGuard<'_, T>, AcquireError> {
+        self.lockdep.acquiring();
+        Hooks(self).acquire_shared(1, deadline, true)?;
+        Ok(ReadLockGuard { lock: self, _marker: PhantomData })
+    }
+
+    /// Blocks until the write lock is taken.
+    ///
+    /// # Panics
+    ///
+    /// If the calling thread holds the read lock but not the write lock, as waiting for
+    /// the other readers could deadlock.
+    pub fn write(&self) -> WriteLockGuard<'_, T> {
+        self.lockdep.acquiring();
+        let _ = self.acquire_write(None, false);
+        WriteLockGuard { lock: self, _marker: PhantomData }
+    }
+
+    pub fn try_write(&self) -> Option<WriteLockGuard<'_, T>> {
+        Hooks(self).acquire_now(1).then(|| WriteLockGuard { lock: self, _marker: PhantomData })
+    }
+
+    /// Blocks until the write lock is taken, `deadline` passes or the thread is interrupted.
+    /// Panics like [`ReentrantReadWriteLock::write`].
+    pub fn write_interruptibly(&self, deadline: Option<Instant>) -> Result<WriteLockGuard<'_, T>, AcquireError> {
+        self.lockdep.acquiring();
+        self.acquire_write(deadline, true)?;
+        Ok(WriteLockGuard { lock: self, _marker: PhantomData })
+    }
+
+    /// Creates a condition to await with write guards of this lock.
+    pub fn new_condition(&self) -> Condition {
+        Condition::new(self.sync.id(), self.sync.wait_strategy().clone())
+    }
+
+    /// Queues for the write lock unless it can be taken right away, counted as a waiting
+    /// writer meanwhile. An interruptible acquisition leaves the fast path to
+    /// [`Synchronizer::acquire`], which looks at the interrupt first.
+    fn acquire_write(&self, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+        if !interruptible && Hooks(self).acquire_now(1) {
+            return Ok(());
+        }
+        self.assert_no_upgrade();
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        let res = Hooks(self).acquire(1, deadline, interruptible);
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        res
+    }
+
+    fn assert_no_upgrade(&self) {
+        assert!(self.read_holds() == 0, "read lock can't be upgraded to the write lock");
+    }
+
+    fn grab_shared(&self) -> bool {
+        let state = self.sync.state();
+        let mut current = state.load(Ordering::Relaxed);
+        loop {
+            if current & WRITE != 0 {
+                return false;
+            }
+            assert!(current != !WRITE, "maximum read lock count exceeded");
+            match state.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    self.add_read_hold(1);
+                    return true;
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn grab_exclusive(&self, id: usize) -> bool {
+        if self.sync.state().compare_exchange(0, WRITE, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        self.writer.store(id, Ordering::Relaxed);
+        self.write_holds.store(1, Ordering::Relaxed);
+        self.sync.deadlock().acquired(1);
+        self.lockdep.acquired(1);
+        true
+    }
+
+    fn unlock_exclusive(&self) {
+        debug_assert_eq!(self.writer.load(Ordering::Relaxed), current_thread_id());
+        if self.write_holds.fetch_sub(1, Ordering::Relaxed) == 1 {
+            Hooks(self).release(1);
+        }
+    }
+
+    fn read_holds(&self) -> usize {
+        READ_HOLDS.with(|holds| {
+            holds.borrow().iter().find(|(id, _)| *id == self.id).map_or(0, |(_, count)| *count)
+        })
+    }
+
+    fn add_read_hold(&self, delta: isize) {
+        if delta > 0 {
+            self.sync.deadlock().acquired(delta as usize);
+            self.lockdep.acquired(delta as usize);
+        } else {
+            self.sync.deadlock().released(delta.unsigned_abs());
+            self.lockdep.released(delta.unsigned_abs());
+        }
+        READ_HOLDS.with(|holds| {
+            let mut holds = holds.borrow_mut();
+            match holds.iter().position(|(id, _)| *id == self.id) {
+                Some(i) if holds[i].1 as isize + delta == 0 => {
+                    holds.swap_remove(i);
+                }
+                Some(i) => holds[i].1 = (holds[i].1 as isize + delta) as usize,
+                None => holds.push((self.id, delta as usize)),
+            }
+        })
+    }
+
+    pub fn is_fair(&self) -> bool {
+        self.fair
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.sync.state().load(Ordering::Relaxed) & WRITE != 0
+    }
+
+    pub fn is_write_locked_by_current_thread(&self) -> bool {
+        self.writer.load(Ordering::Relaxed) == current_thread_id()
+    }
+
+    /// Write guards the calling thread holds.
+    pub fn write_hold_count(&self) -> usize {
+        if self.is_write_locked_by_current_thread() {
+            self.write_holds.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
+    /// Read guards the calling thread holds.
+    pub fn read_hold_count(&self) -> usize {
+        self.read_holds()
+    }
+
+    /// Read guards held by all threads together.
+    pub fn read_lock_count(&self) -> usize {
+        (self.sync.state().load(Ordering::Relaxed) & !WRITE) as usize
+    }
+
+    /// Whether any thread waits for the read or the write lock.
+    pub fn has_queued_threads(&self) -> bool {
+        self.sync.has_queued_threads()
+    }
+}
+
+impl<T: ?Sized> Synchronizer for Hooks<'_, ReentrantReadWriteLock<T>> {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    fn try_acquire(&self, _arg: isize, queued: bool) -> bool {
+        let id = current_thread_id();
+        if self.writer.load(Ordering::Relaxed) == id {
+            self.write_holds.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        self.read_holds() == 0 && (queued || !self.fair || !self.sync.has_queued_threads()) && self.grab_exclusive(id)
+    }
+
+    fn try_release(&self, _arg: isize) -> bool {
+        self.sync.deadlock().released(1);
+        self.lockdep.released(1);
+        self.writer.store(0, Ordering::Relaxed);
+        self.sync.state().fetch_and(!WRITE, Ordering::Release);
+        true
+    }
+
+    /// Readers already holding the lock and the writer always get it. Of the rest a
+    /// success lets the next waiter try too, it may be another reader.
+    fn try_acquire_shared(&self, _arg: isize, queued: bool) -> isize {
+        if self.read_holds() > 0 || self.writer.load(Ordering::Relaxed) == current_thread_id() {
+            let state = self.sync.state();
+            assert!(state.load(Ordering::Relaxed) & !WRITE != !WRITE, "maximum read lock count exceeded");
+            state.fetch_add(1, Ordering::Acquire);
+            self.add_read_hold(1);
+            return 1;
+        }
+        let blocked = !queued
+            && if self.fair {
+                self.sync.has_queued_threads()
+            } else {
+                self.waiting_writers.load(Ordering::Relaxed) > 0
+            };
+        if !blocked && self.grab_shared() {
+            1
+        } else {
+            -1
+        }
+    }
+
+    fn try_release_shared(&self, _arg: isize) -> bool {
+        self.add_read_hold(-1);
+        self.sync.state().fetch_sub(1, Ordering::Release) == 1
+    }
+}
+
+impl<T: ?Sized> Deref for ReadLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+
+impl<T: ?Sized> Drop for ReadLockGuard<'_, T> {
+    fn drop(&mut self) {
+        Hooks(self.lock).release_shared(1);
+    }
+}
+
+impl<T: ?Sized> Deref for WriteLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+
+impl<T: ?Sized> Drop for WriteLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_exclusive();
+    }
+}
+
+impl<T: ?Sized> sealed::ConditionGuard for WriteLockGuard<'_, T> {
+    fn lock_id(&self) -> usize {
+        self.lock.sync.id()
+    }
+
+    fn unlock_fully(&self) -> usize {
+        let holds = self.lock.write_holds.swap(0, Ordering::Relaxed);
+        Hooks(self.lock).release(1);
+        holds
+    }
+
+    fn relock(&self, holds: usize) {
+        // a waiting writer like any other, arriving readers yield to it
+        let _ = self.lock.acquire_write(None, false);
+        self.lock.write_holds.store(holds, Ordering::Relaxed);
+    }
+}
+
+impl<T: ?Sized> ConditionGuard for WriteLockGuard<'_, T> {}
+
+#[cfg(test)]
+mod test {
+    use super::ReentrantReadWriteLock;
+    use crate::lock::{AcquireError, Interrupter};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn rwlock_shared_readers() {
+        let lock = Arc::new(ReentrantReadWriteLock::new(5, false));
+        let barrier = Arc::new(Barrier::new(4));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (lock, barrier) = (lock.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let first = lock.read();
+                    let second = lock.read();
+                    assert_eq!(lock.read_hold_count(), 2);
+                    // every reader is inside at the same time
+                    barrier.wait();
+                    assert!(lock.try_write().is_none());
+                    barrier.wait();
+                    *first + *second
+                })
+            })
+            .collect();
+        assert!(readers.into_iter().all(|r| r.join().unwrap() == 10));
+        assert_eq!(lock.read_lock_count(), 0);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn rwlock_downgrade() {
+        let lock = Arc::new(ReentrantReadWriteLock::new((), true));
+        let outer = lock.write();
+        let inner = lock.write();
+        assert_eq!(lock.write_hold_count(), 2);
+        let read = lock.read();
+        drop(inner);
+        drop(outer);
+        assert!(!lock.is_write_locked());
+        assert_eq!(lock.read_hold_count(), 1);
+        let other = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                assert_eq!(lock.read_hold_count(), 0);
+                assert!(lock.try_write().is_none());
+                let _read = lock.read();
+                lock.read_lock_count()
+            })
+        };
+        assert_eq!(other.join().unwrap(), 2);
+        drop(read);
+        assert_eq!(lock.read_lock_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be upgraded")]
+    fn rwlock_no_upgrade() {
+        let lock = ReentrantReadWriteLock::new((), false);
+        let _read = lock.read();
+        let _write = lock.write();
+    }
+
+    #[test]
+    fn rwlock_exclusion() {
+        for fair in [false, true] {
+            let lock = Arc::new(ReentrantReadWriteLock::new(AtomicUsize::new(0), fair));
+            let handlers: Vec<_> = (0..6)
+                .map(|i| {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        for _ in 0..500 {
+                            if i % 2 == 0 {
+                                let guard = lock.write();
+                                let value = guard.load(Ordering::Relaxed);
+                                let again = lock.write();
+                                guard.store(value + 1, Ordering::Relaxed);
+                                thread::yield_now();
+                                again.store(value + 2, Ordering::Relaxed);
+                            } else {
+                                // never sees a writer halfway through
+                                assert_eq!(lock.read().load(Ordering::Relaxed) % 2, 0);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            handlers.into_iter().for_each(|h| h.join().unwrap());
+            assert_eq!(lock.read().load(Ordering::Relaxed), 3000);
+        }
+    }
+
+    #[test]
+    fn rwlock_interrupted_before_acquiring() {
+        let lock = ReentrantReadWriteLock::new((), false);
+        // a pending interrupt fails the attempt even if the lock is free, and is consumed
+        Interrupter::current().interrupt();
+        assert_eq!(lock.write_interruptibly(None).err(), Some(AcquireError::Interrupted));
+        Interrupter::current().interrupt();
+        assert_eq!(lock.read_interruptibly(None).err(), Some(AcquireError::Interrupted));
+        assert!(lock.write_interruptibly(None).is_ok());
+    }
+
+    #[test]
+    fn rwlock_write_condition() {
+        let lock = Arc::new(ReentrantReadWriteLock::new(AtomicBool::new(false), false));
+        let cond = Arc::new(lock.new_condition());
+        let waiter = {
+            let (lock, cond) = (lock.clone(), cond.clone());
+            thread::spawn(move || {
+                let guard = lock.write();
+                let _again = lock.write();
+                while !guard.load(Ordering::Relaxed) {
+                    cond.r#await(&guard).unwrap();
+                }
+                lock.write_hold_count()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let guard = lock.write();
+        guard.store(true, Ordering::Relaxed);
+        cond.signal();
+        drop(guard);
+        assert_eq!(waiter.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn rwlock_condition_relock_preferred() {
+        let lock = Arc::new(ReentrantReadWriteLock::new((), false));
+        let cond = Arc::new(lock.new_condition());
+        let waiter = {
+            let (lock, cond) = (lock.clone(), cond.clone());
+            thread::spawn(move || {
+                let guard = lock.write();
+                cond.r#await(&guard).unwrap();
+                lock.is_write_locked()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let read = lock.read();
+        cond.signal();
+        // the signalled writer waits for the read lock to retake the write lock
+        thread::sleep(Duration::from_millis(20));
+        let reader = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.try_read().is_none())
+        };
+        assert!(reader.join().unwrap());
+        drop(read);
+        assert!(waiter.join().unwrap());
+    }
+}
@@ -1,122 +1,388 @@
-use std::cell::Cell;
-use std::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
-use std::thread;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{Instant};
-use crate::lock::utils::{Node, State};
+use crate::lock::error::AcquireError;
+use crate::lock::lockdep::LockdepTracker;
+#[cfg(feature = "stats")]
+use crate::lock::stats::LockStats;
+use crate::lock::utils::{cancel_async, poll_acquire_shared, AsyncWait, Hooks, QueuedSynchronizer, Synchronizer};
+use crate::lock::wait::WaitStrategy;
 
 
-pub(crate) struct Semaphore {
-    head: AtomicPtr<Node>,
-    tail: AtomicPtr<Node>,
-    permit: AtomicIsize,
+pub struct Semaphore {
+    // the state holds the available permits
+    sync: QueuedSynchronizer,
+    max_permits: i32,
+    closed: AtomicBool,
     fair: bool,
+    lockdep: LockdepTracker,
+}
+
+/// Permits held on a [`Semaphore`], returned to it when the guard is dropped.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+    permits: isize,
+}
+
+/// Permits held on an `Arc<Semaphore>`, can be moved to and dropped on any thread.
+#[must_use]
+pub struct OwnedSemaphorePermit {
+    sem: Arc<Semaphore>,
+    permits: isize,
+}
+
+/// Future returned by [`Semaphore::acquire_async`]. It waits in the same queue as the
+/// parked threads, dropping it before completion gives up its place.
+#[must_use = "futures do nothing unless polled"]
+pub struct Acquire<'a> {
+    sem: &'a Semaphore,
+    res: isize,
+    wait: AsyncWait,
 }
 
 impl Semaphore {
-    pub(crate) fn new(permits: isize, fair: bool) -> Self {
-        assert!(permits > 0);
-        let node = Box::into_raw(Box::new(Node::new()));
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn new(permits: isize, fair: bool) -> Self {
+        Self::with_max_permits(permits, fair, i32::MAX as isize)
+    }
+
+    /// Creates a semaphore whose available permits never grow past `max_permits`,
+    /// permits released beyond the bound are discarded.
+    ///
+    /// # Panics
+    ///
+    /// If `max_permits` is more than `i32::MAX`, the permits are counted in 32 bits.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn with_max_permits(permits: isize, fair: bool, max_permits: isize) -> Self {
+        assert!(permits > 0 && permits <= max_permits);
+        assert!(max_permits <= i32::MAX as isize, "max_permits must fit in an i32");
         Self {
-            head: AtomicPtr::new(node),
-            tail: AtomicPtr::new(node),
-            permit: AtomicIsize::new(permits),
+            sync: QueuedSynchronizer::new(permits as i32, "Semaphore"),
+            max_permits: max_permits as i32,
+            closed: AtomicBool::new(false),
             fair,
+            lockdep: LockdepTracker::new("Semaphore"),
         }
     }
-    pub(crate) fn acquire(&self, res: isize, deadline: Option<Instant>) -> bool {
-        assert!(res > 0);
-        let mut spins = 0;
-        let mut post_spins = 0;
-        let mut node = std::ptr::null_mut::<Node>();
-        let mut pre_node = std::ptr::null_mut::<Node>();
-        let mut first = false;
-        loop {
-            let tail_node = self.tail.load(Ordering::Acquire);
-            if first || pre_node.is_null() {
-                if self.try_acquire(res) {
-                    if first {
-                        unsafe { (*node).state.set(State::RUNNING) };
-                        self.head.store(node, Ordering::Release);
-                        // 原head泄露
-                    } else if !node.is_null() {
-                        drop(unsafe { Box::from_raw(node) });
-                    }
-                    self.signal_next(self.head.load(Ordering::Relaxed));
-                    return true;
-                }
-            }
 
-            if pre_node.is_null() {
-                if node.is_null() {
-                    node = Box::into_raw(Box::new(Node::new()));
-                }
-                if self.tail.compare_exchange(tail_node, node, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
-                    unsafe{(*tail_node).next=node};
-                    pre_node = tail_node;
-                }
-            } else if post_spins != 0 {
-                post_spins -= 1;
-                std::hint::spin_loop();
-            } else if unsafe { (*node).state.get() == State::RUNNING } {
-                unsafe { (*node).state.set(State::PARK) };
-            } else {
-                spins += 1;
-                post_spins = spins << 1;
-                let (timed, end) = if deadline.is_some() {
-                    (true, deadline.unwrap())
-                } else {
-                    (false, Instant::now())
-                };
-                let now = Instant::now();
-                if !timed {
-                    thread::park();
-                } else if now < end {
-                    thread::park_timeout(end - now);
-                } else {
-                    break;
-                }
-                unsafe { (*node).state.set(State::RUNNING) };
-                first = true;
+    /// Sets how threads wait for permits, by default [`Adaptive`](crate::lock::Adaptive).
+    /// Futures are woken by their waker whatever the strategy.
+    pub fn with_wait_strategy(mut self, strategy: impl WaitStrategy + 'static) -> Self {
+        self.sync.set_wait_strategy(strategy);
+        self
+    }
+
+    /// Blocks until `res` permits are available or `deadline` passes.
+    ///
+    /// # Panics
+    ///
+    /// If `res` is not positive or more than `max_permits`, as it could never be granted.
+    pub fn acquire(&self, res: isize, deadline: Option<Instant>) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_permits(res, deadline, false)?;
+        Ok(SemaphorePermit::new(self, res))
+    }
+
+    /// Same as [`Semaphore::acquire`], but gives up with [`AcquireError::Interrupted`]
+    /// once the thread is interrupted.
+    pub fn acquire_interruptibly(&self, res: isize, deadline: Option<Instant>) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_permits(res, deadline, true)?;
+        Ok(SemaphorePermit::new(self, res))
+    }
+
+    /// Same as [`Semaphore::acquire`], but the permit keeps the semaphore alive.
+    pub fn acquire_owned(self: &Arc<Self>, res: isize, deadline: Option<Instant>) -> Result<OwnedSemaphorePermit, AcquireError> {
+        self.acquire_permits(res, deadline, false)?;
+        Ok(OwnedSemaphorePermit::new(self.clone(), res))
+    }
+
+    pub fn acquire_owned_interruptibly(self: &Arc<Self>, res: isize, deadline: Option<Instant>) -> Result<OwnedSemaphorePermit, AcquireError> {
+        self.acquire_permits(res, deadline, true)?;
+        Ok(OwnedSemaphorePermit::new(self.clone(), res))
+    }
+
+    /// Waits for `res` permits without blocking the thread.
+    ///
+    /// # Panics
+    ///
+    /// If `res` is not positive or more than `max_permits`, like [`Semaphore::acquire`].
+    pub fn acquire_async(&self, res: isize) -> Acquire<'_> {
+        self.check_request(res);
+        Acquire {
+            sem: self,
+            res,
+            wait: AsyncWait::new(),
+        }
+    }
+
+    fn acquire_permits(&self, res: isize, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+        self.check_request(res);
+        self.lockdep.acquiring();
+        Hooks(self).acquire_shared(res, deadline, interruptible)
+    }
+
+    /// Closes the semaphore. Every queued waiter is woken with [`AcquireError::Closed`]
+    /// and later acquisitions fail right away, while held permits can still be released.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.sync.signal_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.sync.stats()
+    }
+
+    /// Returns `res` permits to the semaphore. Permits held by a [`SemaphorePermit`]
+    /// are released automatically, this is only needed after [`SemaphorePermit::forget`].
+    pub fn release(&self, res: isize) {
+        Hooks(self).release_shared(res);
+    }
+
+    /// Adds `n` permits, waking the waiters they satisfy. The count is capped at
+    /// `max_permits`, the number of permits actually added is returned.
+    pub fn add_permits(&self, n: isize) -> isize {
+        let added = self.add(n);
+        self.sync.signal();
+        added
+    }
+
+    fn add(&self, n: isize) -> isize {
+        assert!(n > 0);
+        let permits = self.sync.state();
+        let mut current_permits = permits.load(Ordering::Acquire);
+        loop {
+            let current = current_permits as isize;
+            let next = current.saturating_add(n).min(self.max_permits()).max(current);
+            match permits.compare_exchange_weak(current_permits, next as i32, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return next - current,
+                Err(actual) => current_permits = actual,
             }
         }
-        false
     }
 
-    fn signal_next(&self, h: *mut Node) {
-        let first = unsafe { (*h).next };
-        if !first.is_null() && unsafe { (*first).state.get() == State::PARK } {
-            unsafe { (*first).thread.unpark() };
+    /// Removes `n` permits. The count may go negative, acquisitions are then held
+    /// back until enough permits have been released to bring it above zero again. It
+    /// stops at `-max_permits` though, a reduction past that removes fewer permits.
+    pub fn reduce_permits(&self, n: isize) {
+        assert!(n > 0);
+        let permits = self.sync.state();
+        let mut current_permits = permits.load(Ordering::Acquire);
+        loop {
+            let next = (current_permits as isize).saturating_sub(n).max(-self.max_permits());
+            match permits.compare_exchange_weak(current_permits, next as i32, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current_permits = actual,
+            }
         }
     }
 
-    pub(crate) fn release(&self, res: isize) {
-        assert!(res > 0);
+    /// Takes every available permit at once and returns how many were taken.
+    pub fn drain_permits(&self) -> isize {
+        let permits = self.sync.state();
+        let mut current_permits = permits.load(Ordering::Acquire);
         loop {
-            let current_permits = self.permit.load(Ordering::Acquire);
-            if current_permits + res < current_permits {
-                panic!("permit exceeds the maximum bound");
+            if current_permits <= 0 {
+                return 0;
             }
-            if self.permit.compare_exchange(current_permits, current_permits+res, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
-                break;
+            match permits.compare_exchange_weak(current_permits, 0, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return current_permits as isize,
+                Err(actual) => current_permits = actual,
             }
         }
-        self.signal_next(self.head.load(Ordering::Acquire));
     }
-    pub(crate) fn is_fair(&self) -> bool {
+    pub fn max_permits(&self) -> isize {
+        self.max_permits as isize
+    }
+    pub fn is_fair(&self) -> bool {
         self.fair
     }
-    pub(crate) fn try_acquire(&self, res: isize) -> bool {
-        let current_permit = self.permit.load(Ordering::Relaxed);
-        if current_permit - res > current_permit {
-            panic!("permit exceeds the minimum bound");
+    /// Takes `res` permits if that needs no waiting.
+    ///
+    /// # Panics
+    ///
+    /// If `res` is not positive or more than `max_permits`, like [`Semaphore::acquire`].
+    pub fn try_acquire(&self, res: isize) -> Option<SemaphorePermit<'_>> {
+        self.check_request(res);
+        if Hooks(self).acquire_shared_now(res) {
+            Some(SemaphorePermit::new(self, res))
+        } else {
+            None
         }
-        if self.fair && !unsafe { (*self.head.load(Ordering::Acquire)).next.is_null() } {
+    }
+    /// Same as [`Semaphore::try_acquire`], but the permit keeps the semaphore alive.
+    pub fn try_acquire_owned(self: &Arc<Self>, res: isize) -> Option<OwnedSemaphorePermit> {
+        self.check_request(res);
+        if Hooks(&**self).acquire_shared_now(res) {
+            Some(OwnedSemaphorePermit::new(self.clone(), res))
+        } else {
+            None
+        }
+    }
+    pub fn available_permits(&self) -> isize {
+        self.sync.state().load(Ordering::Relaxed) as isize
+    }
+
+    /// Rejects requests that could never be granted.
+    fn check_request(&self, res: isize) {
+        assert!(res > 0 && res <= self.max_permits(), "requested permits must be positive and at most max_permits");
+    }
+
+    /// Gives up the `held` permits of a guard, returning them unless `forget`.
+    fn drop_permits(&self, held: &mut isize, forget: bool) {
+        let permits = std::mem::take(held);
+        if permits > 0 {
+            self.lockdep.released(permits as usize);
+            if !forget {
+                self.release(permits);
+            }
+        }
+    }
+
+    /// Moves `n` of the `held` permits of a guard out for a new one, false if fewer are held.
+    fn split_permits(&self, held: &mut isize, n: isize) -> bool {
+        assert!(n > 0);
+        if n > *held {
             return false;
         }
-        current_permit - res >= 0 && self.permit.compare_exchange(current_permit, current_permit - res, Ordering::SeqCst, Ordering::Relaxed).is_ok()
+        *held -= n;
+        true
+    }
+
+    /// Moves the `other` permits of a guard onto `held`.
+    fn merge_permits(&self, held: &mut isize, other: &mut isize) {
+        *held += std::mem::take(other);
+    }
+}
+
+impl Synchronizer for Hooks<'_, Semaphore> {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    /// Takes `res` permits, whatever is left may satisfy the next waiter.
+    fn try_acquire_shared(&self, res: isize, queued: bool) -> isize {
+        if self.fair && !queued && self.sync.has_queued_threads() {
+            return -1;
+        }
+        // at most max_permits, checked when requested
+        let res = res as i32;
+        let permits = self.sync.state();
+        let mut current_permits = permits.load(Ordering::Acquire);
+        loop {
+            if current_permits < res {
+                return -1;
+            }
+            match permits.compare_exchange_weak(current_permits, current_permits - res, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return (current_permits - res) as isize,
+                Err(actual) => current_permits = actual,
+            }
+        }
+    }
+
+    fn try_release_shared(&self, res: isize) -> bool {
+        self.add(res);
+        true
+    }
+
+    fn check(&self) -> Result<(), AcquireError> {
+        if self.is_closed() {
+            Err(AcquireError::Closed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (sem, res) = (self.sem, self.res);
+        poll_acquire_shared(&Hooks(sem), res, &mut self.wait, cx).map_ok(|_| SemaphorePermit::new(sem, res))
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        cancel_async(&Hooks(self.sem), &mut self.wait);
+    }
+}
+
+impl<'a> SemaphorePermit<'a> {
+    fn new(sem: &'a Semaphore, permits: isize) -> Self {
+        sem.lockdep.acquired(permits as usize);
+        Self { sem, permits }
+    }
+
+    pub fn num_permits(&self) -> isize {
+        self.permits
+    }
+
+    /// Consumes the guard without returning its permits to the semaphore.
+    pub fn forget(mut self) {
+        self.sem.drop_permits(&mut self.permits, true);
+    }
+
+    /// Moves `n` of the held permits into a new guard, `None` if fewer than `n` are held.
+    pub fn split(&mut self, n: isize) -> Option<Self> {
+        self.sem.split_permits(&mut self.permits, n).then(|| Self { sem: self.sem, permits: n })
     }
-    pub(crate) fn available_permits(&self) -> isize {
-        self.permit.load(Ordering::Relaxed)
+
+    /// Takes over the permits of `other`, both guards must come from the same semaphore.
+    pub fn merge(&mut self, mut other: Self) {
+        assert!(std::ptr::eq(self.sem, other.sem), "merging permits of different semaphores");
+        self.sem.merge_permits(&mut self.permits, &mut other.permits);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.sem.drop_permits(&mut self.permits, false);
+    }
+}
+
+impl OwnedSemaphorePermit {
+    fn new(sem: Arc<Semaphore>, permits: isize) -> Self {
+        sem.lockdep.acquired(permits as usize);
+        Self { sem, permits }
+    }
+
+    pub fn num_permits(&self) -> isize {
+        self.permits
+    }
+
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.sem
+    }
+
+    /// Consumes the guard without returning its permits to the semaphore.
+    pub fn forget(mut self) {
+        self.sem.drop_permits(&mut self.permits, true);
+    }
+
+    /// Moves `n` of the held permits into a new guard, `None` if fewer than `n` are held.
+    pub fn split(&mut self, n: isize) -> Option<Self> {
+        self.sem.split_permits(&mut self.permits, n).then(|| Self { sem: self.sem.clone(), permits: n })
+    }
+
+    /// Takes over the permits of `other`, both guards must come from the same semaphore.
+    pub fn merge(&mut self, mut other: Self) {
+        assert!(Arc::ptr_eq(&self.sem, &other.sem), "merging permits of different semaphores");
+        self.sem.merge_permits(&mut self.permits, &mut other.permits);
+    }
+}
+
+impl Drop for OwnedSemaphorePermit {
+    fn drop(&mut self) {
+        self.sem.drop_permits(&mut self.permits, false);
     }
 }
 
@@ -124,7 +390,32 @@ impl Semaphore {
 #[cfg(test)]
 mod test{
     use super::Semaphore;
-    use std::{thread,sync::Arc, time::Duration};
+    use crate::lock::{interrupted, AcquireError, Interrupter};
+    use std::sync::mpsc;
+    use std::{thread,sync::Arc, time::{Duration, Instant}};
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
     #[test]
     fn semaphore_fair() {
         let arc = Arc::new(Semaphore::new(16, false));
@@ -136,11 +427,10 @@ mod test{
             let join_handler = thread::Builder::new()
                 .name(t_name)
                 .spawn(move || {
-                    semaphore.acquire(4, None);
+                    let _permit = semaphore.acquire(4, None).unwrap();
                     println!("{} is working!", thread::current().name().unwrap());
                     thread::sleep(Duration::new(4, 0));
                     println!("{} complete!", thread::current().name().unwrap());
-                    semaphore.release(4);
                 })
                 .unwrap();
             join_vec.push(join_handler);
@@ -150,17 +440,299 @@ mod test{
         });
     }
 
+    #[test]
     fn semaphore_non_fair(){
-
+        let semaphore = Arc::new(Semaphore::new(2, false));
+        let handlers: Vec<_> = (0..8)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                thread::spawn(move || {
+                    let _permit = semaphore.acquire(1, None).unwrap();
+                    thread::sleep(Duration::from_millis(20));
+                })
+            })
+            .collect();
+        handlers.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(semaphore.available_permits(), 2);
     }
+    #[test]
     fn semaphore_timeout(){
-        
+        let semaphore = Semaphore::new(1, false);
+        let _permit = semaphore.acquire(1, None).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(matches!(semaphore.acquire(1, Some(deadline)), Err(AcquireError::Timeout)));
+        assert!(Instant::now() >= deadline);
+        assert!(!semaphore.sync.has_queued_threads());
     }
+    #[test]
     fn semaphore_try_lock(){
-        
+        let semaphore = Semaphore::new(2, true);
+        let permit = semaphore.try_acquire(2).unwrap();
+        assert!(semaphore.try_acquire(1).is_none());
+        drop(permit);
+        assert!(semaphore.try_acquire(1).is_some());
+    }
+    #[test]
+    #[should_panic(expected = "requested permits must be positive")]
+    fn semaphore_try_acquire_negative(){
+        // would raise the count instead of taking permits
+        let _ = Semaphore::new(2, false).try_acquire(-5);
     }
     #[test]
     fn semaphore_block(){
 
     }
+
+    #[test]
+    fn semaphore_permit_drop(){
+        let semaphore = Semaphore::new(4, false);
+        {
+            let permit = semaphore.acquire(3, None).unwrap();
+            assert_eq!(permit.num_permits(), 3);
+            assert_eq!(semaphore.available_permits(), 1);
+            assert!(semaphore.try_acquire(2).is_none());
+        }
+        assert_eq!(semaphore.available_permits(), 4);
+        semaphore.try_acquire(4).unwrap().forget();
+        assert_eq!(semaphore.available_permits(), 0);
+        semaphore.release(4);
+        assert_eq!(semaphore.available_permits(), 4);
+    }
+    #[test]
+    fn semaphore_permit_split_merge(){
+        let semaphore = Semaphore::new(8, false);
+        let mut permit = semaphore.acquire(6, None).unwrap();
+        assert!(permit.split(7).is_none());
+        let part = permit.split(2).unwrap();
+        assert_eq!(permit.num_permits(), 4);
+        drop(part);
+        assert_eq!(semaphore.available_permits(), 4);
+        let other = semaphore.try_acquire(3).unwrap();
+        permit.merge(other);
+        assert_eq!(permit.num_permits(), 7);
+        assert_eq!(semaphore.available_permits(), 1);
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 8);
+    }
+    #[test]
+    fn semaphore_owned_permit(){
+        fn assert_send<T: Send + 'static>(_: &T) {}
+        let semaphore = Arc::new(Semaphore::new(4, false));
+        let permit = semaphore.acquire_owned(3, None).unwrap();
+        assert_send(&permit);
+        assert!(semaphore.try_acquire_owned(2).is_none());
+        let worker = thread::spawn(move || {
+            assert_eq!(permit.num_permits(), 3);
+            drop(permit);
+        });
+        worker.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 4);
+
+        let mut permit = semaphore.try_acquire_owned(2).unwrap();
+        let part = permit.split(1).unwrap();
+        permit.merge(semaphore.try_acquire_owned(2).unwrap());
+        assert_eq!(permit.num_permits(), 3);
+        drop(part);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+    #[test]
+    fn semaphore_async(){
+        let semaphore = Arc::new(Semaphore::new(2, true));
+        let permit = semaphore.acquire(2, None).unwrap();
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                thread::spawn(move || {
+                    let permit = block_on(semaphore.acquire_async(1)).unwrap();
+                    assert_eq!(permit.num_permits(), 1);
+                    thread::sleep(Duration::from_millis(10));
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        drop(permit);
+        handlers.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+    #[test]
+    #[should_panic(expected = "at most max_permits")]
+    fn semaphore_async_over_max(){
+        // could never complete
+        let semaphore = Semaphore::with_max_permits(1, false, 2);
+        drop(semaphore.acquire_async(3));
+    }
+    #[test]
+    fn semaphore_async_moves_threads(){
+        let semaphore = Semaphore::new(1, false);
+        let permit = semaphore.acquire(1, None).unwrap();
+        let mut future = Box::pin(semaphore.acquire_async(1));
+        assert!(future.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+        // queued on this thread, completed on another
+        thread::scope(|s| {
+            let waiter = s.spawn(move || block_on(future).unwrap().num_permits());
+            thread::sleep(Duration::from_millis(20));
+            drop(permit);
+            assert_eq!(waiter.join().unwrap(), 1);
+        });
+    }
+    #[test]
+    fn semaphore_async_shares_queue(){
+        let semaphore = Arc::new(Semaphore::new(1, true));
+        let permit = semaphore.acquire(1, None).unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // a dropped future leaves the queue
+        let mut dropped = Box::pin(semaphore.acquire_async(1));
+        assert!(dropped.as_mut().poll(&mut cx).is_pending());
+        drop(dropped);
+
+        let mut future = Box::pin(semaphore.acquire_async(1));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        let sync_waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let _permit = semaphore.acquire(1, None).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(permit);
+        // the future was queued first, so the permit went to it
+        let permit = match future.as_mut().poll(&mut cx) {
+            Poll::Ready(permit) => permit.unwrap(),
+            Poll::Pending => panic!("permit was not handed to the first waiter"),
+        };
+        assert!(!sync_waiter.is_finished());
+        drop(permit);
+        sync_waiter.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+    #[test]
+    fn semaphore_timeout_unlinks_waiter(){
+        let semaphore = Arc::new(Semaphore::new(2, true));
+        let mut permit = semaphore.acquire(2, None).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(30);
+        let timed = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || semaphore.acquire(2, Some(deadline)).map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(10));
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let _permit = semaphore.acquire(1, None).unwrap();
+            })
+        };
+        assert_eq!(timed.join().unwrap(), Err(AcquireError::Timeout));
+        // the cancelled head no longer blocks the smaller request behind it
+        drop(permit.split(1));
+        waiter.join().unwrap();
+        assert!(!semaphore.sync.has_queued_threads());
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+    #[test]
+    fn semaphore_resize(){
+        let semaphore = Arc::new(Semaphore::with_max_permits(4, false, 6));
+        assert_eq!(semaphore.add_permits(1), 1);
+        assert_eq!(semaphore.add_permits(5), 1);
+        assert_eq!(semaphore.available_permits(), 6);
+
+        semaphore.reduce_permits(8);
+        assert_eq!(semaphore.available_permits(), -2);
+        assert!(semaphore.try_acquire(1).is_none());
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                semaphore.acquire(1, None).unwrap().forget();
+            })
+        };
+        semaphore.release(2);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        semaphore.release(1);
+        waiter.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 0);
+
+        semaphore.add_permits(5);
+        let permit = semaphore.try_acquire(2).unwrap();
+        assert_eq!(semaphore.drain_permits(), 3);
+        assert_eq!(semaphore.drain_permits(), 0);
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+    #[test]
+    fn semaphore_close(){
+        let semaphore = Arc::new(Semaphore::new(2, false));
+        let permit = semaphore.acquire(2, None).unwrap();
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                thread::spawn(move || semaphore.acquire(1, None).map(|_| ()))
+            })
+            .collect();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut future = Box::pin(semaphore.acquire_async(1));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        thread::sleep(Duration::from_millis(30));
+
+        semaphore.close();
+        assert!(semaphore.is_closed());
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Closed));
+        }
+        assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Err(AcquireError::Closed))));
+        assert!(matches!(semaphore.acquire(1, None), Err(AcquireError::Closed)));
+        assert!(semaphore.try_acquire(1).is_none());
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+    #[cfg(feature = "stats")]
+    #[test]
+    fn semaphore_stats(){
+        let semaphore = Arc::new(Semaphore::new(1, false));
+        let permit = semaphore.acquire(1, None).unwrap();
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let _permit = semaphore.acquire(1, None).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(semaphore.stats().queue_length, 1);
+        drop(permit);
+        waiter.join().unwrap();
+        let stats = semaphore.stats();
+        assert_eq!(stats.acquires, 2);
+        assert_eq!(stats.contended, 1);
+        assert_eq!(stats.spin_acquires + stats.park_acquires, 1);
+        assert_eq!(stats.queue_length, 0);
+        assert_eq!(stats.wait_histogram.iter().sum::<u64>(), 1);
+    }
+    #[test]
+    fn semaphore_interrupt(){
+        let semaphore = Arc::new(Semaphore::new(1, true));
+        let permit = semaphore.acquire(1, None).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                tx.send(Interrupter::current()).unwrap();
+                let res = semaphore.acquire_interruptibly(1, None).map(|_| ());
+                // the flag was consumed by the failed acquisition
+                (res, interrupted())
+            })
+        };
+        let interrupter = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        interrupter.interrupt();
+        assert_eq!(waiter.join().unwrap(), (Err(AcquireError::Interrupted), false));
+        // the waiter left the queue, a fair acquisition is not held back by it
+        drop(permit);
+        assert!(semaphore.try_acquire(1).is_some());
+
+        Interrupter::current().interrupt();
+        assert!(matches!(semaphore.acquire_interruptibly(1, None), Err(AcquireError::Interrupted)));
+        assert!(!interrupted());
+    }
 }
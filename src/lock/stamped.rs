@@ -0,0 +1,342 @@
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::Instant;
+use crate::lock::error::AcquireError;
+use crate::lock::utils::{Hooks, QueuedSynchronizer, Synchronizer};
+use crate::lock::wait::WaitStrategy;
+
+// state: read holds in the low bits, then the write bit, then a version bumped by every
+// write unlock so optimistic stamps taken before it no longer validate
+const LG_READERS: u32 = 16;
+const WBIT: u64 = 1 << LG_READERS;
+const RBITS: u64 = WBIT - 1;
+const ABITS: u64 = RBITS | WBIT;
+const SBITS: u64 = !RBITS;
+// where the version starts over after wrapping, 0 is never a valid stamp
+const ORIGIN: u64 = WBIT << 1;
+
+/// Proof of a [`StampedLock`] acquisition, or of an optimistic read to validate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp(u64);
+
+/// Capability based read-write lock with optimistic reads.
+///
+/// An optimistic read takes no lock and writes nothing, readers check afterwards with
+/// [`StampedLock::validate`] that no writer got in meanwhile and fall back to a read lock
+/// if one did. The lock guards no data itself: whatever is read optimistically has to be
+/// atomics, as a writer may change it during the read. It is not reentrant.
+pub struct StampedLock {
+    // queue only, the stamps need the full 64 bits of their own state
+    sync: QueuedSynchronizer,
+    state: AtomicU64,
+}
+
+impl StampedLock {
+    pub fn new() -> Self {
+        Self {
+            sync: QueuedSynchronizer::new(0, "StampedLock"),
+            state: AtomicU64::new(ORIGIN),
+        }
+    }
+
+    /// Sets how threads wait for the lock, by default [`Adaptive`](crate::lock::Adaptive).
+    pub fn with_wait_strategy(mut self, strategy: impl WaitStrategy + 'static) -> Self {
+        self.sync.set_wait_strategy(strategy);
+        self
+    }
+
+    /// Blocks until the lock is exclusively held, returns the stamp to unlock it with.
+    pub fn write_lock(&self) -> Stamp {
+        let _ = Hooks(self).acquire(0, None, false);
+        self.held_stamp()
+    }
+
+    pub fn try_write_lock(&self) -> Option<Stamp> {
+        Hooks(self).acquire_now(0).then(|| self.held_stamp())
+    }
+
+    /// Blocks until the lock is exclusively held, `deadline` passes or the thread is interrupted.
+    pub fn write_lock_interruptibly(&self, deadline: Option<Instant>) -> Result<Stamp, AcquireError> {
+        Hooks(self).acquire(0, deadline, true).map(|_| self.held_stamp())
+    }
+
+    /// Blocks until the lock is held for reading, returns the stamp to unlock it with.
+    pub fn read_lock(&self) -> Stamp {
+        let _ = Hooks(self).acquire_shared(0, None, false);
+        self.held_stamp()
+    }
+
+    /// Takes a read lock unless the lock is write locked or a thread is waiting for it.
+    pub fn try_read_lock(&self) -> Option<Stamp> {
+        Hooks(self).acquire_shared_now(0).then(|| self.held_stamp())
+    }
+
+    /// Blocks until the lock is held for reading, `deadline` passes or the thread is interrupted.
+    pub fn read_lock_interruptibly(&self, deadline: Option<Instant>) -> Result<Stamp, AcquireError> {
+        Hooks(self).acquire_shared(0, deadline, true).map(|_| self.held_stamp())
+    }
+
+    /// Stamp of the lock the calling thread just took. Holding it keeps the version and
+    /// the write bit as they are, while other readers only change the read holds.
+    fn held_stamp(&self) -> Stamp {
+        Stamp(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Stamp to [`validate`](StampedLock::validate) an optimistic read with, `None` while
+    /// the lock is write locked.
+    pub fn try_optimistic_read(&self) -> Option<Stamp> {
+        let state = self.state.load(Ordering::Acquire);
+        if state & WBIT == 0 {
+            Some(Stamp(state & SBITS))
+        } else {
+            None
+        }
+    }
+
+    /// Whether no write lock was taken since `stamp` was issued. Always true for the stamp
+    /// of a lock that is still held.
+    pub fn validate(&self, stamp: Stamp) -> bool {
+        // orders the optimistic reads before the check
+        fence(Ordering::Acquire);
+        stamp.0 & SBITS == self.state.load(Ordering::Relaxed) & SBITS
+    }
+
+    /// Upgrades `stamp` to a write lock without waiting. Works for a write stamp, for a
+    /// read stamp if it is the only read hold and for a still valid optimistic stamp. On
+    /// success the returned stamp replaces `stamp`, otherwise `stamp` stays as it was.
+    pub fn try_convert_to_write_lock(&self, stamp: Stamp) -> Option<Stamp> {
+        let held = stamp.0 & ABITS;
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if stamp.0 & SBITS != state & SBITS {
+                return None;
+            }
+            let next = if held & WBIT != 0 {
+                return Some(stamp);
+            } else if held == 0 && state & RBITS == 0 {
+                state + WBIT
+            } else if held != 0 && state & RBITS == 1 {
+                state - 1 + WBIT
+            } else {
+                return None;
+            };
+            match self.state.compare_exchange_weak(state, next, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    Self::fence_writes();
+                    return Some(Stamp(next));
+                }
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Called once the write lock is taken. An optimistic reader that sees any write made
+    /// under the lock then also sees the write bit when validating.
+    fn fence_writes() {
+        fence(Ordering::Release);
+    }
+
+    /// # Panics
+    ///
+    /// If `stamp` is not the stamp of the current write lock.
+    pub fn unlock_write(&self, stamp: Stamp) {
+        let state = self.state.load(Ordering::Relaxed);
+        assert!(state == stamp.0 && state & WBIT != 0, "stamp does not match the write lock");
+        Hooks(self).release(0);
+    }
+
+    /// # Panics
+    ///
+    /// If `stamp` is not the stamp of a read lock that is held.
+    pub fn unlock_read(&self, stamp: Stamp) {
+        let state = self.state.load(Ordering::Relaxed);
+        let held = stamp.0 & ABITS;
+        assert!(
+            stamp.0 & SBITS == state & SBITS && held != 0 && held & WBIT == 0 && state & RBITS != 0,
+            "stamp does not match a read lock"
+        );
+        Hooks(self).release_shared(0);
+    }
+
+    /// Releases the read or write lock `stamp` belongs to.
+    pub fn unlock(&self, stamp: Stamp) {
+        if stamp.0 & WBIT != 0 {
+            self.unlock_write(stamp);
+        } else {
+            self.unlock_read(stamp);
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WBIT != 0
+    }
+
+    pub fn is_read_locked(&self) -> bool {
+        self.read_lock_count() != 0
+    }
+
+    pub fn read_lock_count(&self) -> usize {
+        (self.state.load(Ordering::Relaxed) & RBITS) as usize
+    }
+
+}
+
+impl Synchronizer for Hooks<'_, StampedLock> {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    /// Writers barge in whenever the lock is free.
+    fn try_acquire(&self, _arg: isize, _queued: bool) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & ABITS != 0 || self.state.compare_exchange(state, state + WBIT, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        StampedLock::fence_writes();
+        true
+    }
+
+    /// Bumps the version, so no optimistic stamp from before validates.
+    fn try_release(&self, _arg: isize) -> bool {
+        let next = match self.state.load(Ordering::Relaxed).wrapping_add(WBIT) {
+            0 => ORIGIN,
+            next => next,
+        };
+        self.state.store(next, Ordering::Release);
+        true
+    }
+
+    /// Readers don't barge past waiting threads, one that got in lets the next waiter try.
+    fn try_acquire_shared(&self, _arg: isize, queued: bool) -> isize {
+        if !queued && self.sync.has_queued_threads() {
+            return -1;
+        }
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WBIT != 0 {
+                return -1;
+            }
+            assert!(state & RBITS != RBITS, "too many read locks");
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return 1,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    fn try_release_shared(&self, _arg: isize) -> bool {
+        self.state.fetch_sub(1, Ordering::Release) & RBITS == 1
+    }
+}
+
+impl Default for StampedLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StampedLock;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::{sync::Arc, thread, time::{Duration, Instant}};
+
+    #[test]
+    fn stamped_optimistic_read() {
+        let lock = StampedLock::new();
+        let stamp = lock.try_optimistic_read().unwrap();
+        assert!(lock.validate(stamp));
+        let read = lock.read_lock();
+        assert!(lock.validate(stamp) && lock.validate(read));
+        assert!(lock.try_write_lock().is_none());
+        lock.unlock(read);
+
+        let write = lock.write_lock();
+        assert!(lock.try_optimistic_read().is_none());
+        assert!(lock.try_read_lock().is_none());
+        lock.unlock_write(write);
+        // a write happened since
+        assert!(!lock.validate(stamp));
+        assert!(lock.validate(lock.try_optimistic_read().unwrap()));
+    }
+
+    #[test]
+    fn stamped_convert_to_write() {
+        let lock = StampedLock::new();
+        let optimistic = lock.try_optimistic_read().unwrap();
+        let write = lock.try_convert_to_write_lock(optimistic).unwrap();
+        assert!(lock.is_write_locked());
+        assert_eq!(lock.try_convert_to_write_lock(write), Some(write));
+        lock.unlock_write(write);
+        // invalidated by that write
+        assert!(lock.try_convert_to_write_lock(optimistic).is_none());
+
+        let first = lock.read_lock();
+        let second = lock.read_lock();
+        assert!(lock.try_convert_to_write_lock(first).is_none());
+        lock.unlock_read(second);
+        let write = lock.try_convert_to_write_lock(first).unwrap();
+        assert_eq!(lock.read_lock_count(), 0);
+        lock.unlock(write);
+        assert!(!lock.is_write_locked() && !lock.is_read_locked());
+    }
+
+    #[test]
+    fn stamped_consistent_reads() {
+        let lock = Arc::new(StampedLock::new());
+        let pair = Arc::new((AtomicU64::new(0), AtomicU64::new(0)));
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let (lock, pair) = (lock.clone(), pair.clone());
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let stamp = lock.write_lock();
+                        let value = pair.0.load(Ordering::Relaxed) + 1;
+                        pair.0.store(value, Ordering::Relaxed);
+                        thread::yield_now();
+                        pair.1.store(value, Ordering::Relaxed);
+                        lock.unlock_write(stamp);
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let (lock, pair) = (lock.clone(), pair.clone());
+                thread::spawn(move || {
+                    let mut optimistic = 0;
+                    for _ in 0..2000 {
+                        if let Some(stamp) = lock.try_optimistic_read() {
+                            let (a, b) = (pair.0.load(Ordering::Relaxed), pair.1.load(Ordering::Relaxed));
+                            if lock.validate(stamp) {
+                                assert_eq!(a, b);
+                                optimistic += 1;
+                                continue;
+                            }
+                        }
+                        let stamp = lock.read_lock();
+                        assert_eq!(pair.0.load(Ordering::Relaxed), pair.1.load(Ordering::Relaxed));
+                        lock.unlock_read(stamp);
+                    }
+                    optimistic
+                })
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        let optimistic: usize = readers.into_iter().map(|r| r.join().unwrap()).sum();
+        assert!(optimistic > 0);
+        assert_eq!(pair.1.load(Ordering::Relaxed), 1000);
+    }
+
+    #[test]
+    fn stamped_timeout() {
+        let lock = Arc::new(StampedLock::new());
+        let write = lock.write_lock();
+        let reader = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.read_lock_interruptibly(Some(Instant::now() + Duration::from_millis(20))).is_err())
+        };
+        assert!(reader.join().unwrap());
+        lock.unlock_write(write);
+        assert!(lock.try_read_lock().is_some());
+    }
+}
@@ -0,0 +1,155 @@
+//! Contention statistics, only collected with the `stats` feature. Without it the
+//! collector is a zero sized type whose methods compile to nothing.
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Number of wait time buckets. Bucket `i` counts waits in `[2^(i-1), 2^i)` microseconds,
+/// the last one everything longer.
+#[cfg(feature = "stats")]
+pub const WAIT_BUCKETS: usize = 20;
+
+/// Snapshot of the statistics of one primitive, see `stats()` on each of them.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockStats {
+    /// Successful acquisitions.
+    pub acquires: u64,
+    /// Acquisitions that could not be satisfied right away and had to queue.
+    pub contended: u64,
+    /// Contended acquisitions that completed while spinning, without parking.
+    pub spin_acquires: u64,
+    /// Contended acquisitions that parked at least once.
+    pub park_acquires: u64,
+    /// Waiters queued when the snapshot was taken.
+    pub queue_length: usize,
+    /// Wait time histogram of the contended acquisitions.
+    pub wait_histogram: [u64; WAIT_BUCKETS],
+}
+
+#[cfg(feature = "stats")]
+pub(crate) struct StatsCollector {
+    acquires: AtomicU64,
+    contended: AtomicU64,
+    spin_acquires: AtomicU64,
+    park_acquires: AtomicU64,
+    wait_histogram: [AtomicU64; WAIT_BUCKETS],
+}
+
+#[cfg(not(feature = "stats"))]
+pub(crate) struct StatsCollector;
+
+#[cfg(feature = "stats")]
+impl StatsCollector {
+    pub(crate) const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            acquires: ZERO,
+            contended: ZERO,
+            spin_acquires: ZERO,
+            park_acquires: ZERO,
+            wait_histogram: [ZERO; WAIT_BUCKETS],
+        }
+    }
+
+    /// Start of a contended wait.
+    pub(crate) fn wait_start(&self) -> Option<Instant> {
+        Some(Instant::now())
+    }
+
+    pub(crate) fn uncontended(&self) {
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn contended(&self, start: Option<Instant>, parked: bool) {
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        if parked {
+            self.park_acquires.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.spin_acquires.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(start) = start {
+            let micros = start.elapsed().as_micros() as u64;
+            let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+            self.wait_histogram[bucket.min(WAIT_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self, queue_length: usize) -> LockStats {
+        LockStats {
+            acquires: self.acquires.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spin_acquires: self.spin_acquires.load(Ordering::Relaxed),
+            park_acquires: self.park_acquires.load(Ordering::Relaxed),
+            queue_length,
+            wait_histogram: std::array::from_fn(|i| self.wait_histogram[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl StatsCollector {
+    pub(crate) const fn new() -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub(crate) fn wait_start(&self) -> Option<Instant> {
+        None
+    }
+
+    #[inline(always)]
+    pub(crate) fn uncontended(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn contended(&self, _start: Option<Instant>, _parked: bool) {}
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod test {
+    use super::StatsCollector;
+    use crate::lock::{Park, ReentrantLock, Spin, WaitStrategy};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn stats_histogram() {
+        let stats = StatsCollector::new();
+        stats.uncontended();
+        stats.contended(Some(Instant::now()), false);
+        stats.contended(Some(Instant::now() - Duration::from_millis(3)), true);
+        let snapshot = stats.snapshot(2);
+        assert_eq!(snapshot.acquires, 3);
+        assert_eq!(snapshot.contended, 2);
+        assert_eq!(snapshot.spin_acquires, 1);
+        assert_eq!(snapshot.park_acquires, 1);
+        assert_eq!(snapshot.queue_length, 2);
+        // 3ms falls in [2^11, 2^12) microseconds
+        assert_eq!(snapshot.wait_histogram[12], 1);
+        assert_eq!(snapshot.wait_histogram.iter().sum::<u64>(), 2);
+    }
+
+    fn contended_once(strategy: impl WaitStrategy + 'static) -> (u64, u64) {
+        let lock = Arc::new(ReentrantLock::new((), false).with_wait_strategy(strategy));
+        let guard = lock.lock();
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || drop(lock.lock()))
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        waiter.join().unwrap();
+        let stats = lock.stats();
+        (stats.spin_acquires, stats.park_acquires)
+    }
+
+    #[test]
+    fn stats_parks_counted() {
+        // spin rounds are not parks
+        assert_eq!(contended_once(Spin), (1, 0));
+        assert_eq!(contended_once(Park), (0, 1));
+    }
+}
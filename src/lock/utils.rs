@@ -1,51 +1,868 @@
 use std::cell::Cell;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use crate::lock::deadlock::DeadlockTracker;
+use crate::lock::error::AcquireError;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use crate::lock::futex;
+use crate::lock::interrupt;
+use crate::lock::parking_lot;
+#[cfg(feature = "stats")]
+use crate::lock::stats::LockStats;
+use crate::lock::stats::StatsCollector;
+use crate::lock::wait::{Adaptive, Parker, WaitStrategy};
 
 
 
-#[derive(Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u8)]
 pub(crate) enum State {
     PARK,
     RUNNING,
+    /// Dequeued by the releasing side, which already handed the resource over.
+    ACQUIRED,
+    /// Gave up waiting (timeout or dropped future) and unlinked itself.
+    CANCELLED,
 }
 
+impl State {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => State::PARK,
+            1 => State::RUNNING,
+            2 => State::ACQUIRED,
+            _ => State::CANCELLED,
+        }
+    }
+}
+
+/// How a queued waiter is woken up: a parked thread, a pending task or a thread sleeping
+/// on the state word of its synchronizer through a wake bit.
+#[derive(Clone)]
+pub(crate) enum Waiter {
+    Thread(thread::Thread),
+    Task(Waker),
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    Futex(*const AtomicU64, u32),
+}
+
+impl Waiter {
+    pub(crate) fn wake(&self) {
+        match self {
+            Waiter::Thread(thread) => thread.unpark(),
+            Waiter::Task(waker) => waker.wake_by_ref(),
+            #[cfg(all(feature = "futex", target_os = "linux"))]
+            Waiter::Futex(word, bit) => {
+                futex::wake(unsafe { &**word }, *bit);
+            }
+        }
+    }
+}
+
+/// A waiter in a [`WaitQueue`]. `prev`, `next` and `waiter` are only touched while the
+/// queue is locked, `state` is read by the owning waiter without the lock. `key` tells
+/// apart the waiters of different primitives sharing a queue of the parking lot.
 pub(crate) struct Node {
+    pub(crate) prev: *mut Node,
     pub(crate) next: *mut Node,
-    pub(crate) thread: thread::Thread,
-    pub(crate) state: Cell<State>,
+    pub(crate) waiter: Waiter,
+    pub(crate) state: AtomicU8,
+    pub(crate) acquires: isize,
+    pub(crate) key: usize,
 }
 
 impl Node {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(acquires: isize) -> Self {
+        Self::with_waiter(Waiter::Thread(thread::current()), acquires)
+    }
+    pub(crate) fn with_waker(waker: Waker, acquires: isize) -> Self {
+        Self::with_waiter(Waiter::Task(waker), acquires)
+    }
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    pub(crate) fn with_futex(word: &AtomicU64, acquires: isize) -> Self {
+        Self::with_waiter(Waiter::Futex(word, futex::bit()), acquires)
+    }
+    fn with_waiter(waiter: Waiter, acquires: isize) -> Self {
         Self {
+            prev: std::ptr::null_mut(),
             next: std::ptr::null_mut(),
-            thread: thread::current(),
-            state: Cell::new(State::RUNNING),
+            waiter,
+            state: AtomicU8::new(State::RUNNING as u8),
+            acquires,
+            key: 0,
+        }
+    }
+    pub(crate) fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Acquire))
+    }
+    pub(crate) fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release)
+    }
+}
+
+/// FIFO list of waiters guarded by a small spin lock, so that waiters can leave the
+/// middle of the queue when they time out or their future is dropped. A queue of the
+/// parking lot holds the waiters of several keys, a guard only sees those of its own.
+pub(crate) struct WaitQueue {
+    head: AtomicPtr<Node>,
+    tail: AtomicPtr<Node>,
+    locked: AtomicBool,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(std::ptr::null_mut()),
+            tail: AtomicPtr::new(std::ptr::null_mut()),
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> WaitQueueGuard<'_> {
+        self.lock_for(0)
+    }
+
+    /// Locks the queue for the waiters of `key`.
+    pub(crate) fn lock_for(&self, key: usize) -> WaitQueueGuard<'_> {
+        let backoff = Backoff::new();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            backoff.spin_heavy();
+        }
+        WaitQueueGuard { queue: self, key, woken: Vec::new() }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+
+}
+
+/// Locked [`WaitQueue`]. The waiters it wakes are only woken once it is dropped, so that
+/// no waker runs with the queue locked.
+pub(crate) struct WaitQueueGuard<'a> {
+    queue: &'a WaitQueue,
+    key: usize,
+    woken: Vec<Waiter>,
+}
+
+impl WaitQueueGuard<'_> {
+    pub(crate) fn first(&self) -> *mut Node {
+        self.skip_others(self.queue.head.load(Ordering::Relaxed))
+    }
+
+    /// The waiter of the key queued after `node`.
+    pub(crate) fn next(&self, node: *mut Node) -> *mut Node {
+        self.skip_others(unsafe { (*node).next })
+    }
+
+    fn skip_others(&self, mut node: *mut Node) -> *mut Node {
+        while !node.is_null() && unsafe { (*node).key } != self.key {
+            node = unsafe { (*node).next };
+        }
+        node
+    }
+
+    /// Number of queued waiters of the key.
+    pub(crate) fn len(&self) -> usize {
+        let mut len = 0;
+        let mut node = self.first();
+        while !node.is_null() {
+            len += 1;
+            node = self.next(node);
+        }
+        len
+    }
+
+    /// Links `node` at the tail and marks it parked.
+    pub(crate) fn push_back(&mut self, node: *mut Node) {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        unsafe {
+            (*node).prev = tail;
+            (*node).next = std::ptr::null_mut();
+            (*node).key = self.key;
+            (*node).set_state(State::PARK);
+        }
+        if tail.is_null() {
+            self.queue.head.store(node, Ordering::Release);
+        } else {
+            unsafe { (*tail).next = node };
+        }
+        self.queue.tail.store(node, Ordering::Relaxed);
+    }
+
+    /// Unlinks a node that is currently queued. The caller sets its new state.
+    pub(crate) fn remove(&mut self, node: *mut Node) {
+        let (prev, next) = unsafe { ((*node).prev, (*node).next) };
+        if prev.is_null() {
+            self.queue.head.store(next, Ordering::Release);
+        } else {
+            unsafe { (*prev).next = next };
+        }
+        if next.is_null() {
+            self.queue.tail.store(prev, Ordering::Relaxed);
+        } else {
+            unsafe { (*next).prev = prev };
+        }
+        unsafe {
+            (*node).prev = std::ptr::null_mut();
+            (*node).next = std::ptr::null_mut();
+        }
+    }
+
+    /// Moves a parked `node` to RUNNING and wakes its waiter, leaving it queued.
+    pub(crate) fn signal(&mut self, node: *mut Node) {
+        if unsafe { (*node).state() } == State::PARK {
+            self.woken.push(unsafe { (*node).waiter.clone() });
+            unsafe { (*node).set_state(State::RUNNING) };
+        }
+    }
+
+    /// Unlinks `node`, moves it to `state` and wakes its waiter. The node must not be
+    /// touched afterwards, its owner may free it as soon as it sees the new state.
+    pub(crate) fn wake(&mut self, node: *mut Node, state: State) {
+        self.remove(node);
+        self.woken.push(unsafe { (*node).waiter.clone() });
+        unsafe { (*node).set_state(state) };
+    }
+}
+
+impl Drop for WaitQueueGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.locked.store(false, Ordering::Release);
+        for waiter in self.woken.drain(..) {
+            waiter.wake();
+        }
+    }
+}
+
+/// Implements [`Synchronizer`] for a primitive of the crate, whose hooks must not be part
+/// of its public interface. Derefs to the primitive.
+pub(crate) struct Hooks<'a, P: ?Sized>(pub(crate) &'a P);
+
+impl<P: ?Sized> Deref for Hooks<'_, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        self.0
+    }
+}
+
+/// Whether a waiter takes a synchronizer for itself or shares it with other waiters.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Mode {
+    Exclusive,
+    Shared,
+}
+
+/// State of a [`QueuedSynchronizer`], a signed 32-bit value with the atomic operations the
+/// hooks need. It is the low half of a 64-bit word whose high half counts the wake-ups of
+/// the queued waiters, which with the `futex` feature on Linux sleep on that half, so the
+/// operations leave it alone. A carry out of the state may bump it, which only costs a
+/// spurious wake-up.
+pub struct StateWord(AtomicU64);
+
+const STATE: u64 = u32::MAX as u64;
+
+impl StateWord {
+    const fn new(state: i32) -> Self {
+        Self(AtomicU64::new(state as u32 as u64))
+    }
+
+    fn state(word: u64) -> i32 {
+        (word & STATE) as u32 as i32
+    }
+
+    fn with_state(word: u64, state: i32) -> u64 {
+        (word & !STATE) | u64::from(state as u32)
+    }
+
+    /// The whole word, for the futex to sleep on its wake-up half.
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    pub(crate) fn word(&self) -> &AtomicU64 {
+        &self.0
+    }
+
+    pub fn load(&self, order: Ordering) -> i32 {
+        Self::state(self.0.load(order))
+    }
+
+    pub fn store(&self, state: i32, order: Ordering) {
+        let _ = self.0.fetch_update(order, Ordering::Relaxed, |word| Some(Self::with_state(word, state)));
+    }
+
+    pub fn compare_exchange(&self, current: i32, new: i32, success: Ordering, failure: Ordering) -> Result<i32, i32> {
+        loop {
+            match self.compare_exchange_weak(current, new, success, failure) {
+                Err(actual) if actual == current => continue,
+                res => return res,
+            }
+        }
+    }
+
+    /// Like [`StateWord::compare_exchange`], but may fail spuriously, also when a wake-up
+    /// changed the other half of the word meanwhile.
+    pub fn compare_exchange_weak(&self, current: i32, new: i32, success: Ordering, failure: Ordering) -> Result<i32, i32> {
+        let word = self.0.load(failure);
+        if Self::state(word) != current {
+            return Err(Self::state(word));
+        }
+        self.0
+            .compare_exchange_weak(word, Self::with_state(word, new), success, failure)
+            .map(Self::state)
+            .map_err(Self::state)
+    }
+
+    /// Adds to the state, wrapping around on overflow.
+    pub fn fetch_add(&self, val: i32, order: Ordering) -> i32 {
+        // a negative value carries into the wake-ups rather than borrowing from them, they
+        // must never go back to a count a sleeper may have read
+        Self::state(self.0.fetch_add(u64::from(val as u32), order))
+    }
+
+    /// Subtracts from the state, wrapping around on overflow.
+    pub fn fetch_sub(&self, val: i32, order: Ordering) -> i32 {
+        self.fetch_add(val.wrapping_neg(), order)
+    }
+
+    pub fn fetch_and(&self, val: i32, order: Ordering) -> i32 {
+        Self::state(self.0.fetch_and(!STATE | u64::from(val as u32), order))
+    }
+
+    pub fn fetch_or(&self, val: i32, order: Ordering) -> i32 {
+        Self::state(self.0.fetch_or(u64::from(val as u32), order))
+    }
+
+    pub fn fetch_update<F>(&self, set_order: Ordering, fetch_order: Ordering, mut f: F) -> Result<i32, i32>
+    where
+        F: FnMut(i32) -> Option<i32>,
+    {
+        self.0
+            .fetch_update(set_order, fetch_order, |word| f(Self::state(word)).map(|state| Self::with_state(word, state)))
+            .map(Self::state)
+            .map_err(Self::state)
+    }
+}
+
+/// State word of a lock built on the [`Synchronizer`] hooks, in the manner of Java's
+/// `AbstractQueuedSynchronizer`. What the state means is up to the hooks, some keep their
+/// own atomics next to it. The waiters queue in the parking lot under the address of the
+/// synchronizer.
+///
+/// Only the first queued waiter tries to acquire, a release wakes it and it stays at the
+/// head until it succeeds, gives up or fails, which advances the head. A successful shared
+/// acquisition that leaves something for the next waiter wakes it in turn.
+///
+/// Queued threads keep their node on the stack. With the `futex` feature on Linux they
+/// sleep on the wake-up half of the state word instead of their park token, so the node
+/// carries no thread handle.
+///
+/// The state word and a pointer are all it holds without the `stats`, `deadlock` and
+/// `lockdep` features. What only waiters and conditions need is allocated by the first of
+/// them, an uncontended synchronizer never allocates.
+///
+/// Custom synchronizers embed one and implement [`Synchronizer`] on top, see there.
+pub struct QueuedSynchronizer {
+    state: StateWord,
+    stats: StatsCollector,
+    deadlock: DeadlockTracker,
+    // null until first needed, owned by the synchronizer
+    shared: AtomicPtr<Shared>,
+}
+
+/// The part of a [`QueuedSynchronizer`] allocated on demand.
+struct Shared {
+    id: usize,
+    strategy: Arc<dyn WaitStrategy>,
+}
+
+// source of `QueuedSynchronizer::id`
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Shared {
+    fn new(strategy: Arc<dyn WaitStrategy>) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            strategy,
+        }
+    }
+}
+
+impl QueuedSynchronizer {
+    /// `kind` names the primitive in deadlock reports.
+    pub fn new(state: i32, kind: &'static str) -> Self {
+        Self {
+            state: StateWord::new(state),
+            stats: StatsCollector::new(),
+            deadlock: DeadlockTracker::new(kind),
+            shared: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    fn shared(&self) -> &Shared {
+        let shared = self.shared.load(Ordering::Acquire);
+        if !shared.is_null() {
+            return unsafe { &*shared };
+        }
+        let new = Box::into_raw(Box::new(Shared::new(Arc::new(Adaptive::new()))));
+        match self.shared.compare_exchange(std::ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => unsafe { &*new },
+            Err(installed) => {
+                drop(unsafe { Box::from_raw(new) });
+                unsafe { &*installed }
+            }
+        }
+    }
+
+    /// How queued threads wait, shared with the conditions of the lock.
+    pub(crate) fn wait_strategy(&self) -> &Arc<dyn WaitStrategy> {
+        &self.shared().strategy
+    }
+
+    /// Tells the synchronizer apart from others, also after it moved or changed its wait
+    /// strategy.
+    pub(crate) fn id(&self) -> usize {
+        self.shared().id
+    }
+
+    /// Sets how queued threads wait, by default [`Adaptive`].
+    pub fn set_wait_strategy(&mut self, strategy: impl WaitStrategy + 'static) {
+        let strategy = Arc::new(strategy);
+        let shared = self.shared.get_mut();
+        if shared.is_null() {
+            *shared = Box::into_raw(Box::new(Shared::new(strategy)));
+        } else {
+            unsafe { (**shared).strategy = strategy };
+        }
+    }
+
+    /// The state word, for the hooks to read and update however they need.
+    pub fn state(&self) -> &StateWord {
+        &self.state
+    }
+
+    /// Sets the state to `new` if it holds `expected`, with acquire and release ordering.
+    pub fn compare_and_set_state(&self, expected: i32, new: i32) -> bool {
+        self.state.compare_exchange(expected, new, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    /// The queue of the parking lot the waiters are in, not locked.
+    fn bucket(&self) -> &'static WaitQueue {
+        parking_lot::bucket(self as *const Self as usize)
+    }
+
+    fn queue(&self) -> WaitQueueGuard<'static> {
+        self.bucket().lock_for(self as *const Self as usize)
+    }
+
+    /// Whether any thread or future waits to acquire.
+    pub fn has_queued_threads(&self) -> bool {
+        !self.bucket().is_empty() && !self.queue().first().is_null()
+    }
+
+    pub(crate) fn deadlock(&self) -> &DeadlockTracker {
+        &self.deadlock
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.stats.snapshot(self.queue().len())
+    }
+
+    /// The queue node of the calling thread.
+    fn node(&self, arg: isize) -> Node {
+        #[cfg(all(feature = "futex", target_os = "linux"))]
+        return Node::with_futex(self.state.word(), arg);
+        #[cfg(not(all(feature = "futex", target_os = "linux")))]
+        Node::new(arg)
+    }
+
+    /// Parks a thread queued with a [`QueuedSynchronizer::node`], taken before it checks
+    /// whether it was woken.
+    fn parker(&self, deadline: Option<Instant>) -> Parker<'_> {
+        #[cfg(all(feature = "futex", target_os = "linux"))]
+        return Parker::futex(self.state.word(), deadline);
+        #[cfg(not(all(feature = "futex", target_os = "linux")))]
+        Parker::new(deadline)
+    }
+
+    /// Wakes the first waiter to retry, after the state changed in its favour.
+    pub fn signal(&self) {
+        // pairs with the fence in `attempt`: either the waiter sees the new state or we
+        // see it queued
+        fence(Ordering::SeqCst);
+        if self.bucket().is_empty() {
+            return;
+        }
+        let mut queue = self.queue();
+        let first = queue.first();
+        if !first.is_null() {
+            queue.signal(first);
+        }
+    }
+
+    /// Wakes every waiter to retry, for a [`Synchronizer::check`] that now fails them all.
+    pub fn signal_all(&self) {
+        let mut queue = self.queue();
+        let mut node = queue.first();
+        while !node.is_null() {
+            let next = queue.next(node);
+            queue.signal(node);
+            node = next;
+        }
+    }
+}
+
+impl Drop for QueuedSynchronizer {
+    fn drop(&mut self) {
+        let shared = *self.shared.get_mut();
+        if !shared.is_null() {
+            drop(unsafe { Box::from_raw(shared) });
         }
     }
 }
 
+/// The hooks of a lock built on a [`QueuedSynchronizer`], which the provided methods turn
+/// into blocking, timed and interruptible acquisitions. A lock implements the exclusive
+/// pair, the shared pair or both, the other pair panics.
+///
+/// The acquire hooks are called for arriving threads with `queued` false and for the
+/// first queued waiter with `queued` true, so a fair lock only holds back the former.
+/// Neither runs with the queue locked, the hooks may call any method of the synchronizer.
+///
+/// The provided methods are the lock's own business, keep the type implementing this
+/// trait private if calling them from outside could break the lock, e.g. a release by a
+/// thread that does not hold it.
+pub trait Synchronizer {
+    /// The synchronizer whose state the hooks work on and whose queue waiters wait in.
+    fn sync(&self) -> &QueuedSynchronizer;
+
+    /// Tries to take the lock exclusively.
+    fn try_acquire(&self, _arg: isize, _queued: bool) -> bool {
+        unreachable!("no exclusive mode")
+    }
+
+    /// Releases an exclusive hold, true if that may let a waiter acquire.
+    fn try_release(&self, _arg: isize) -> bool {
+        unreachable!("no exclusive mode")
+    }
+
+    /// Tries to take a share. Negative on failure, zero on a success that leaves nothing
+    /// for the next waiter and positive if it may succeed too.
+    fn try_acquire_shared(&self, _arg: isize, _queued: bool) -> isize {
+        unreachable!("no shared mode")
+    }
+
+    /// Releases a share, true if that may let a waiter acquire.
+    fn try_release_shared(&self, _arg: isize) -> bool {
+        unreachable!("no shared mode")
+    }
+
+    /// Fails arriving and queued acquisitions alike, e.g. once the lock is closed. Call
+    /// [`QueuedSynchronizer::signal_all`] when it starts failing.
+    fn check(&self) -> Result<(), AcquireError> {
+        Ok(())
+    }
+
+    /// Acquires exclusively if that needs no waiting.
+    fn acquire_now(&self, arg: isize) -> bool {
+        let acquired = self.check().is_ok() && self.try_acquire(arg, false);
+        if acquired {
+            self.sync().stats.uncontended();
+        }
+        acquired
+    }
+
+    /// Acquires exclusively, queueing until `deadline` passes or, if `interruptible`,
+    /// the thread is interrupted.
+    fn acquire(&self, arg: isize, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+        if interruptible && interrupt::interrupted() {
+            return Err(AcquireError::Interrupted);
+        }
+        self.check()?;
+        if self.acquire_now(arg) {
+            return Ok(());
+        }
+        acquire_queued(self, Mode::Exclusive, arg, deadline, interruptible)
+    }
+
+    fn release(&self, arg: isize) -> bool {
+        let released = self.try_release(arg);
+        if released {
+            self.sync().signal();
+        }
+        released
+    }
+
+    fn acquire_shared_now(&self, arg: isize) -> bool {
+        let acquired = self.check().is_ok() && self.try_acquire_shared(arg, false) >= 0;
+        if acquired {
+            self.sync().stats.uncontended();
+        }
+        acquired
+    }
+
+    /// Shared counterpart of [`Synchronizer::acquire`].
+    fn acquire_shared(&self, arg: isize, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+        if interruptible && interrupt::interrupted() {
+            return Err(AcquireError::Interrupted);
+        }
+        self.check()?;
+        if self.acquire_shared_now(arg) {
+            return Ok(());
+        }
+        acquire_queued(self, Mode::Shared, arg, deadline, interruptible)
+    }
+
+    fn release_shared(&self, arg: isize) -> bool {
+        let released = self.try_release_shared(arg);
+        if released {
+            self.sync().signal();
+        }
+        released
+    }
+}
+
+/// Polls a shared acquisition of a future, queueing it in `wait` on the first poll.
+pub(crate) fn poll_acquire_shared<S: Synchronizer + ?Sized>(s: &S, arg: isize, wait: &mut AsyncWait, cx: &mut Context<'_>) -> Poll<Result<(), AcquireError>> {
+    let sync = s.sync();
+    if wait.node.is_null() {
+        s.check()?;
+        if s.acquire_shared_now(arg) {
+            return Poll::Ready(Ok(()));
+        }
+        wait.start = sync.stats.wait_start();
+        wait.node = Box::into_raw(Box::new(Node::with_waker(cx.waker().clone(), arg)));
+        sync.queue().push_back(wait.node);
+    }
+    let node = wait.node;
+    {
+        // updated under the queue lock, where a release reads it, and before the attempt
+        // so that a release after it wakes the current task
+        let _queue = sync.queue();
+        let waiter = unsafe { &mut (*node).waiter };
+        match waiter {
+            Waiter::Task(waker) if waker.will_wake(cx.waker()) => {}
+            _ => *waiter = Waiter::Task(cx.waker().clone()),
+        }
+    }
+    let Some(res) = attempt(s, node, Mode::Shared, arg) else {
+        return Poll::Pending;
+    };
+    if res.is_ok() {
+        sync.stats.contended(wait.start, true);
+    }
+    wait.node = std::ptr::null_mut();
+    drop(unsafe { Box::from_raw(node) });
+    Poll::Ready(res)
+}
+
+/// Gives up the queued acquisition of a dropped future.
+pub(crate) fn cancel_async<S: Synchronizer + ?Sized>(s: &S, wait: &mut AsyncWait) {
+    if wait.node.is_null() {
+        return;
+    }
+    cancel(s.sync(), wait.node);
+    drop(unsafe { Box::from_raw(wait.node) });
+    wait.node = std::ptr::null_mut();
+}
+
+/// The queue node of a pending future, see [`poll_acquire_shared`].
+pub(crate) struct AsyncWait {
+    node: *mut Node,
+    start: Option<Instant>,
+}
+
+// SAFETY: the node is a heap allocation owned by the future, not tied to the thread that
+// queued it. The queue only touches it with its bucket locked, and the future only does
+// with the bucket locked or after unlinking it, so a poll or drop on another thread can't
+// race a release. Its waker is `Send + Sync` and its key and counts are plain words.
+unsafe impl Send for AsyncWait {}
+// SAFETY: every use of the node takes `&mut AsyncWait`, a shared reference can't reach it.
+unsafe impl Sync for AsyncWait {}
+
+impl AsyncWait {
+    pub(crate) const fn new() -> Self {
+        Self {
+            node: std::ptr::null_mut(),
+            start: None,
+        }
+    }
+}
+
+/// Tries to acquire for a queued `node`, which has to be first for that. Unlinks it and
+/// returns the outcome once it acquired or `check` failed, otherwise leaves it parked.
+/// The hooks run with the queue unlocked, a release meanwhile may signal the node after
+/// it acquired, which is passed on to the next waiter.
+fn attempt<S: Synchronizer + ?Sized>(s: &S, node: *mut Node, mode: Mode, arg: isize) -> Option<Result<(), AcquireError>> {
+    let sync = s.sync();
+    unsafe { (*node).set_state(State::PARK) };
+    // pairs with the fence in `signal`: either we see the state released or the releasing
+    // thread sees us parked
+    fence(Ordering::SeqCst);
+    if let Err(err) = s.check() {
+        let mut queue = sync.queue();
+        let first = queue.first() == node;
+        queue.remove(node);
+        if first {
+            signal_first(&mut queue);
+        }
+        return Some(Err(err));
+    }
+    // only the node itself unlinks it, it stays first while the hooks run
+    if sync.queue().first() != node {
+        return None;
+    }
+    let acquired = match mode {
+        Mode::Exclusive => s.try_acquire(arg, true).then_some(0),
+        Mode::Shared => Some(s.try_acquire_shared(arg, true)).filter(|r| *r >= 0),
+    }?;
+    let mut queue = sync.queue();
+    let signalled = unsafe { (*node).state() } == State::RUNNING;
+    queue.remove(node);
+    // the head advanced, a shared success may let the next waiter through as well
+    if acquired > 0 || signalled {
+        signal_first(&mut queue);
+    }
+    Some(Ok(()))
+}
+
+fn signal_first(queue: &mut WaitQueueGuard<'_>) {
+    let first = queue.first();
+    if !first.is_null() {
+        queue.signal(first);
+    }
+}
+
+/// Unlinks a node that gives up. If it was first, or woken to retry, the next waiter
+/// retries in its place.
+fn cancel(sync: &QueuedSynchronizer, node: *mut Node) {
+    let mut queue = sync.queue();
+    let pass_on = queue.first() == node || unsafe { (*node).state() } == State::RUNNING;
+    queue.remove(node);
+    unsafe { (*node).set_state(State::CANCELLED) };
+    if pass_on {
+        signal_first(&mut queue);
+    }
+}
+
+fn acquire_queued<S: Synchronizer + ?Sized>(s: &S, mode: Mode, arg: isize, deadline: Option<Instant>, interruptible: bool) -> Result<(), AcquireError> {
+    let sync = s.sync();
+    let start = sync.stats.wait_start();
+    // a timed wait ends by itself
+    if deadline.is_none() {
+        sync.deadlock.wait();
+    }
+    let mut node = sync.node(arg);
+    let node: *mut Node = &mut node;
+    sync.queue().push_back(node);
+    // the node lives on this stack frame, a panicking hook or strategy must not leave it queued
+    let queued = Queued { sync, node, interruptible };
+    let queued_at = Instant::now();
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    if interruptible {
+        interrupt::sleep_on(Some(sync.state.word()));
+    }
+    let strategy = sync.wait_strategy();
+    let backoff = Backoff::new();
+    let mut parked = false;
+    let res = 'retry: loop {
+        if let Some(res) = attempt(s, node, mode, arg) {
+            break res;
+        }
+        loop {
+            let parker = sync.parker(deadline);
+            if unsafe { (*node).state() } != State::PARK {
+                break;
+            }
+            let interrupted = interruptible && interrupt::is_interrupted();
+            if interrupted || deadline.is_some_and(|end| end <= Instant::now()) {
+                cancel(sync, node);
+                if interrupted {
+                    interrupt::interrupted();
+                    break 'retry Err(AcquireError::Interrupted);
+                }
+                break 'retry Err(AcquireError::Timeout);
+            }
+            strategy.wait(&backoff, &parker);
+            parked |= parker.parked();
+        }
+    };
+    std::mem::forget(queued);
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    if interruptible {
+        interrupt::sleep_on(None);
+    }
+    sync.deadlock.wait_done();
+    if res.is_ok() {
+        strategy.waited(queued_at.elapsed());
+        sync.stats.contended(start, parked);
+    }
+    res
+}
+
+/// Unlinks a queued node when unwinding.
+struct Queued<'a> {
+    sync: &'a QueuedSynchronizer,
+    node: *mut Node,
+    #[cfg_attr(not(all(feature = "futex", target_os = "linux")), allow(dead_code))]
+    interruptible: bool,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        cancel(self.sync, self.node);
+        #[cfg(all(feature = "futex", target_os = "linux"))]
+        if self.interruptible {
+            interrupt::sleep_on(None);
+        }
+        self.sync.deadlock.wait_done();
+    }
+}
+
 const STEP_LIMIT:u32=6;
 
-pub(crate) struct Backoff{
-    step:Cell<u32>
+/// Exponential backoff for spinning, one per wait. Each call spins longer than the last,
+/// up to a limit after which [`Backoff::is_complete`] tells to stop spinning.
+pub struct Backoff{
+    step:Cell<u32>,
+    started:Cell<Option<Instant>>
 }
 
 impl Backoff {
-    pub(crate) fn new()->Self{
+    pub fn new()->Self{
         Self{
-            step:Cell::new(0)
+            step:Cell::new(0),
+            started:Cell::new(None)
+        }
+    }
+    /// Time since the first call, which starts the clock.
+    pub fn elapsed(&self)->Duration{
+        match self.started.get() {
+            Some(started)=>started.elapsed(),
+            None=>{
+                self.started.set(Some(Instant::now()));
+                Duration::ZERO
+            }
         }
     }
-    pub(crate) fn spin_light(&self){
+    pub fn spin_light(&self){
         let step=self.step.get().min(STEP_LIMIT).pow(2);
         for _ in 0..step{
             std::hint::spin_loop();
         }
         self.step.set(self.step.get()+1)
     }
-    pub(crate) fn spin_heavy(&self){
+    /// Spins like [`Backoff::spin_light`] until complete, then yields the thread instead.
+    pub fn spin_heavy(&self){
         let step=self.step.get().min(STEP_LIMIT).pow(2);
         if self.step.get()<=STEP_LIMIT {
             for _ in 0..step{
@@ -56,8 +873,92 @@ impl Backoff {
         }
         self.step.set(self.step.get()+1)
     }
-    pub(crate) fn is_complete(&self)->bool{
+    pub fn is_complete(&self)->bool{
         self.step.get()>STEP_LIMIT
     }
 }
 
+impl Default for Backoff {
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{QueuedSynchronizer, StateWord, Synchronizer};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Opened once for good, the state is 1 when open.
+    struct Latch(QueuedSynchronizer);
+
+    impl Synchronizer for Latch {
+        fn sync(&self) -> &QueuedSynchronizer {
+            &self.0
+        }
+
+        fn try_acquire_shared(&self, _arg: isize, _queued: bool) -> isize {
+            if self.0.state().load(Ordering::Acquire) == 1 { 1 } else { -1 }
+        }
+
+        fn try_release_shared(&self, _arg: isize) -> bool {
+            self.0.state().store(1, Ordering::Release);
+            true
+        }
+    }
+
+    #[test]
+    fn synchronizer_shared_propagates() {
+        let latch = Arc::new(Latch(QueuedSynchronizer::new(0, "Latch")));
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let latch = latch.clone();
+                thread::spawn(move || latch.acquire_shared(1, None, false))
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        assert!(latch.sync().has_queued_threads());
+        // one release, each waiter that passes wakes the next
+        latch.release_shared(1);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Ok(()));
+        }
+        assert!(latch.acquire_shared_now(1));
+    }
+
+    #[test]
+    fn state_word_keeps_wakeups() {
+        let state = StateWord::new(-1);
+        state.0.fetch_add(5 << 32, Ordering::Relaxed);
+        // wrapping to 0 carries into the wake-ups, going below it does not borrow
+        assert_eq!(state.fetch_add(1, Ordering::Relaxed), -1);
+        assert_eq!(state.fetch_sub(2, Ordering::Relaxed), 0);
+        assert_eq!(state.compare_exchange(-2, i32::MIN, Ordering::Relaxed, Ordering::Relaxed), Ok(-2));
+        assert_eq!(state.fetch_and(!i32::MIN, Ordering::Relaxed), i32::MIN);
+        assert_eq!(state.fetch_or(3, Ordering::Relaxed), 0);
+        state.store(7, Ordering::Relaxed);
+        assert_eq!(state.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| Some(s * 2)), Ok(7));
+        assert_eq!(state.load(Ordering::Relaxed), 14);
+        assert_eq!(state.0.load(Ordering::Relaxed) >> 32, 6);
+    }
+
+    #[test]
+    #[cfg(all(target_pointer_width = "64", not(any(feature = "stats", feature = "deadlock", feature = "lockdep"))))]
+    fn synchronizer_size() {
+        use crate::lock::{CountDownLatch, ReentrantLock, Semaphore};
+        use std::mem::size_of;
+        // the state word and the pointer to what waiters need
+        assert_eq!(size_of::<QueuedSynchronizer>(), 16);
+        assert_eq!(size_of::<Semaphore>(), 24);
+        assert_eq!(size_of::<CountDownLatch>(), 16);
+        assert_eq!(size_of::<ReentrantLock<()>>(), 32);
+        // allocated by the first waiter only
+        let latch = Latch(QueuedSynchronizer::new(1, "Latch"));
+        assert!(latch.acquire_shared_now(1));
+        assert!(latch.0.shared.load(Ordering::Relaxed).is_null());
+    }
+}
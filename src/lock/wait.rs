@@ -0,0 +1,281 @@
+//! How blocked threads pass the time until they are woken. Every blocking primitive waits
+//! in a loop that checks for its wake-up, deadline and interrupts between calls to its
+//! [`WaitStrategy`], so a strategy may return early whenever it likes.
+use std::cell::Cell;
+use std::marker::PhantomData;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use std::sync::atomic::fence;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use crate::lock::futex;
+use crate::lock::utils::Backoff;
+
+/// Waits a little for the waiting thread to be woken. `backoff` is fresh for every blocking
+/// call of a primitive and kept over its rounds, so the strategy can tell how long it waits.
+pub trait WaitStrategy: Send + Sync {
+    fn wait(&self, backoff: &Backoff, parker: &Parker<'_>);
+
+    /// A thread of a lock, semaphore or latch got through after waiting for `waited`.
+    fn waited(&self, _waited: Duration) {}
+}
+
+/// Puts a waiting thread to sleep for a [`WaitStrategy`] until it is woken or the deadline
+/// of a timed wait passes, whatever the primitive wakes its waiters with. Wake-ups can be
+/// spurious.
+pub struct Parker<'a> {
+    deadline: Option<Instant>,
+    // the state word, its wake-ups before the waiter last checked for its own and the
+    // waiter's wake bit
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    futex: Option<(&'a AtomicU64, u32, u32)>,
+    parked: Cell<bool>,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl Parker<'_> {
+    /// Parks with the thread's park token.
+    pub(crate) fn new(deadline: Option<Instant>) -> Self {
+        Self {
+            deadline,
+            #[cfg(all(feature = "futex", target_os = "linux"))]
+            futex: None,
+            parked: Cell::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the strategy parked with it, as opposed to only spinning or yielding.
+    pub fn parked(&self) -> bool {
+        self.parked.get()
+    }
+
+    pub fn park(&self) {
+        self.parked.set(true);
+        #[cfg(all(feature = "futex", target_os = "linux"))]
+        if let Some((word, expected, bit)) = self.futex {
+            futex::wait(word, expected, bit, self.deadline);
+            return;
+        }
+        match self.deadline {
+            None => thread::park(),
+            Some(end) => thread::park_timeout(end.saturating_duration_since(Instant::now())),
+        }
+    }
+}
+
+#[cfg(all(feature = "futex", target_os = "linux"))]
+impl<'a> Parker<'a> {
+    /// Sleeps on the state word `word` through the calling thread's wake bit. Create it
+    /// before checking for the wake-up, a wake-up in between then keeps it from sleeping.
+    pub(crate) fn futex(word: &'a AtomicU64, deadline: Option<Instant>) -> Self {
+        let expected = futex::wakeups(word);
+        // pairs with the fence in `futex::wake`
+        fence(Ordering::SeqCst);
+        Self {
+            deadline,
+            futex: Some((word, expected, futex::bit())),
+            parked: Cell::new(false),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Busy spins, never giving up the CPU. Lowest latency, but only for waits that are known
+/// to be short and threads that have a core to themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spin;
+
+/// Spins with growing pauses, then yields the CPU between checks without ever parking.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinThenYield;
+
+/// Spins with growing pauses for a while, then parks. The default of the barrier, phaser
+/// and exchanger.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinThenPark;
+
+/// Spins about as long as the waits of its primitive took lately, then parks. The default
+/// of the locks, semaphores and latches, each learning on its own.
+///
+/// Waits that usually outlast [`Adaptive::MAX_SPIN`] park right away, spinning would only
+/// burn the time. Neither does a thread spin when the spinning threads would take every
+/// core, leaving none to the thread it waits for, so a single core never spins.
+#[derive(Debug)]
+pub struct Adaptive {
+    // moving average of the waits, in nanoseconds
+    average: AtomicU32,
+}
+
+/// Parks right away, leaving the CPU to other threads at the cost of a slower wake-up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Park;
+
+impl WaitStrategy for Spin {
+    fn wait(&self, backoff: &Backoff, _parker: &Parker<'_>) {
+        backoff.spin_light();
+    }
+}
+
+impl WaitStrategy for SpinThenYield {
+    fn wait(&self, backoff: &Backoff, _parker: &Parker<'_>) {
+        backoff.spin_heavy();
+    }
+}
+
+impl WaitStrategy for SpinThenPark {
+    fn wait(&self, backoff: &Backoff, parker: &Parker<'_>) {
+        if backoff.is_complete() {
+            parker.park();
+        } else {
+            backoff.spin_light();
+        }
+    }
+}
+
+impl Adaptive {
+    /// Longest average wait still worth spinning for.
+    pub const MAX_SPIN: Duration = Duration::from_micros(50);
+
+    pub const fn new() -> Self {
+        // a few microseconds until the first waits tell better
+        Self { average: AtomicU32::new(2_000) }
+    }
+
+    /// Moving average of the waits.
+    pub fn average(&self) -> Duration {
+        Duration::from_nanos(self.average.load(Ordering::Relaxed).into())
+    }
+
+    /// How long a wait spins before parking.
+    fn spin_budget(&self) -> Duration {
+        let average = self.average();
+        if average > Self::MAX_SPIN {
+            Duration::ZERO
+        } else {
+            (average * 2).min(Self::MAX_SPIN)
+        }
+    }
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// threads spinning in an adaptive wait
+static SPINNING: AtomicUsize = AtomicUsize::new(0);
+
+impl WaitStrategy for Adaptive {
+    fn wait(&self, backoff: &Backoff, parker: &Parker<'_>) {
+        if backoff.elapsed() < self.spin_budget() {
+            let spinning = SPINNING.fetch_add(1, Ordering::Relaxed) + 1;
+            if spinning < parallelism() {
+                backoff.spin_light();
+                SPINNING.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+            SPINNING.fetch_sub(1, Ordering::Relaxed);
+        }
+        parker.park();
+    }
+
+    fn waited(&self, waited: Duration) {
+        // weighs the new wait 1/8, racing updates only lose a sample
+        let waited = waited.as_nanos().min(u32::MAX.into()) as i64;
+        let average = i64::from(self.average.load(Ordering::Relaxed));
+        self.average.store((average + (waited - average) / 8) as u32, Ordering::Relaxed);
+    }
+}
+
+fn parallelism() -> usize {
+    static PARALLELISM: OnceLock<usize> = OnceLock::new();
+    *PARALLELISM.get_or_init(|| thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+impl WaitStrategy for Park {
+    fn wait(&self, _backoff: &Backoff, parker: &Parker<'_>) {
+        parker.park();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Adaptive, Park, Spin, SpinThenPark, SpinThenYield, WaitStrategy};
+    use crate::lock::{CountDownLatch, CyclicBarrier, ReentrantLock, Semaphore};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn check<W: WaitStrategy + 'static>(strategy: impl Fn() -> W) {
+        let lock = Arc::new(ReentrantLock::new(Cell::new(0), false).with_wait_strategy(strategy()));
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let guard = lock.lock();
+                        guard.set(guard.get() + 1);
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(lock.lock().get(), 600);
+
+        let latch = Arc::new(CountDownLatch::new(1).with_wait_strategy(strategy()));
+        let barrier = Arc::new(CyclicBarrier::new(2).with_wait_strategy(strategy()));
+        let waiter = {
+            let (latch, barrier) = (latch.clone(), barrier.clone());
+            thread::spawn(move || {
+                latch.wait();
+                barrier.wait().unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(5));
+        latch.count_down();
+        barrier.wait().unwrap();
+        waiter.join().unwrap();
+
+        // timed waits end on time whatever the strategy
+        let semaphore = Semaphore::new(1, false).with_wait_strategy(strategy());
+        let _permit = semaphore.acquire(1, None).unwrap();
+        let start = Instant::now();
+        assert!(semaphore.acquire(1, Some(start + Duration::from_millis(20))).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn wait_strategies() {
+        check(|| Spin);
+        check(|| SpinThenYield);
+        check(|| SpinThenPark);
+        check(|| Park);
+        check(Adaptive::new);
+    }
+
+    #[test]
+    fn adaptive_learns() {
+        let adaptive = Adaptive::new();
+        // short waits are caught spinning about twice as long
+        (0..64).for_each(|_| adaptive.waited(Duration::from_micros(5)));
+        assert!(adaptive.average().abs_diff(Duration::from_micros(5)) < Duration::from_micros(1));
+        assert!(adaptive.spin_budget() >= Duration::from_micros(8));
+        // long ones are not worth spinning for
+        (0..64).for_each(|_| adaptive.waited(Duration::from_millis(1)));
+        assert!(adaptive.average() > Adaptive::MAX_SPIN);
+        assert_eq!(adaptive.spin_budget(), Duration::ZERO);
+        (0..64).for_each(|_| adaptive.waited(Duration::ZERO));
+        assert!(adaptive.spin_budget() < Duration::from_micros(1));
+    }
+}
@@ -1,11 +1,6 @@
 use std::thread;
 use std::time::Duration;
 
-struct Stu<'a> {
-    name: &'a str,
-    age: u8,
-}
-
 fn main() {
     let t1 = thread::spawn(|| {
        // thread::park_timeout(Duration::from_secs(4));
@@ -20,6 +15,4 @@ fn main() {
         t1.join().unwrap();
     });
     t2.join().unwrap();
-
-    use std::any::{Any,TypeId};
 }
@@ -0,0 +1,165 @@
+//! Synchronizers built outside the crate on its `QueuedSynchronizer` and `Synchronizer`.
+
+use multi_thread::lock::{AcquireError, QueuedSynchronizer, Synchronizer};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Non-reentrant mutex, the state is 1 while locked.
+struct Mutex {
+    sync: QueuedSynchronizer,
+}
+
+impl Synchronizer for Mutex {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    fn try_acquire(&self, _arg: isize, _queued: bool) -> bool {
+        self.sync.compare_and_set_state(0, 1)
+    }
+
+    fn try_release(&self, _arg: isize) -> bool {
+        self.sync.state().store(0, Ordering::Release);
+        true
+    }
+}
+
+/// Gate that lets everyone through once opened, or fails everyone once closed.
+struct Gate {
+    sync: QueuedSynchronizer,
+    closed: AtomicBool,
+}
+
+impl Gate {
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.sync.signal_all();
+    }
+}
+
+impl Synchronizer for Gate {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    fn try_acquire_shared(&self, _arg: isize, _queued: bool) -> isize {
+        if self.sync.state().load(Ordering::Acquire) == 1 { 1 } else { -1 }
+    }
+
+    fn try_release_shared(&self, _arg: isize) -> bool {
+        self.sync.state().store(1, Ordering::Release);
+        true
+    }
+
+    fn check(&self) -> Result<(), AcquireError> {
+        if self.closed.load(Ordering::SeqCst) {
+            Err(AcquireError::Closed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn custom_mutex() {
+    let mutex = Arc::new((Mutex { sync: QueuedSynchronizer::new(0, "Mutex") }, AtomicUsize::new(0)));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    mutex.0.acquire(1, None, false).unwrap();
+                    let value = mutex.1.load(Ordering::Relaxed);
+                    thread::yield_now();
+                    mutex.1.store(value + 1, Ordering::Relaxed);
+                    mutex.0.release(1);
+                }
+            })
+        })
+        .collect();
+    threads.into_iter().for_each(|t| t.join().unwrap());
+    assert_eq!(mutex.1.load(Ordering::Relaxed), 2000);
+
+    assert!(mutex.0.acquire_now(1));
+    let deadline = Instant::now() + Duration::from_millis(10);
+    assert_eq!(mutex.0.acquire(1, Some(deadline), false), Err(AcquireError::Timeout));
+    assert!(!mutex.0.sync().has_queued_threads());
+
+    // a timed out first waiter passes its turn on to the one behind
+    let timed = {
+        let mutex = mutex.clone();
+        thread::spawn(move || mutex.0.acquire(1, Some(Instant::now() + Duration::from_millis(30)), false))
+    };
+    thread::sleep(Duration::from_millis(10));
+    let waiter = {
+        let mutex = mutex.clone();
+        thread::spawn(move || mutex.0.acquire(1, None, false))
+    };
+    assert_eq!(timed.join().unwrap(), Err(AcquireError::Timeout));
+    mutex.0.release(1);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+    assert!(!mutex.0.sync().has_queued_threads());
+}
+
+/// Mutex whose queued attempts look at the queue they are in.
+struct QueueProbe {
+    sync: QueuedSynchronizer,
+    queued_seen: AtomicBool,
+}
+
+impl Synchronizer for QueueProbe {
+    fn sync(&self) -> &QueuedSynchronizer {
+        &self.sync
+    }
+
+    fn try_acquire(&self, _arg: isize, queued: bool) -> bool {
+        if queued && self.sync.has_queued_threads() {
+            self.queued_seen.store(true, Ordering::SeqCst);
+        }
+        self.sync.compare_and_set_state(0, 1)
+    }
+
+    fn try_release(&self, _arg: isize) -> bool {
+        self.sync.state().store(0, Ordering::Release);
+        true
+    }
+}
+
+#[test]
+fn custom_hooks_use_queue() {
+    let probe = Arc::new(QueueProbe { sync: QueuedSynchronizer::new(0, "QueueProbe"), queued_seen: AtomicBool::new(false) });
+    assert!(probe.acquire_now(1));
+    let waiter = {
+        let probe = probe.clone();
+        thread::spawn(move || probe.acquire(1, None, false))
+    };
+    thread::sleep(Duration::from_millis(20));
+    probe.release(1);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+    assert!(probe.queued_seen.load(Ordering::SeqCst));
+}
+
+#[test]
+fn custom_gate() {
+    let new_gate = || Arc::new(Gate { sync: QueuedSynchronizer::new(0, "Gate"), closed: AtomicBool::new(false) });
+    for open in [true, false] {
+        let gate = new_gate();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let gate = gate.clone();
+                thread::spawn(move || gate.acquire_shared(1, None, false))
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        assert!(gate.sync().has_queued_threads());
+        if open {
+            gate.release_shared(1);
+        } else {
+            gate.close();
+        }
+        let expected = if open { Ok(()) } else { Err(AcquireError::Closed) };
+        assert!(waiters.into_iter().all(|w| w.join().unwrap() == expected));
+    }
+}